@@ -31,6 +31,11 @@ impl Inode {
         }
     }
 
+    /// Where the disk inode is, the same for all the `Inode`s of a file
+    pub fn location(&self) -> (usize, usize) {
+        (self.block_id, self.block_offset)
+    }
+
     fn read_disk_inode<V>(&self, f: impl FnOnce(&DiskInode) -> V) -> V {
         get_block_cache(self.block_id, Arc::clone(&self.block_device))
             .lock()
@@ -172,6 +177,12 @@ impl Inode {
         });
     }
 
+    /// the size of the file in bytes
+    pub fn size(&self) -> usize {
+        let _fs = self.fs.lock();
+        self.read_disk_inode(|disk_inode| disk_inode.size as usize)
+    }
+
     pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> usize {
         let _fs = self.fs.lock();
         self.read_disk_inode(|disk_inode| disk_inode.read_at(offset, buf, &self.block_device))
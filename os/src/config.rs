@@ -1,14 +1,42 @@
-pub const USER_STACK_SIZE: usize = 4096 * 2;
+pub const USER_STACK_SIZE: usize = 4096 * 2; // mapped at first, the stack grows on faults
+/// the default RLIMIT_STACK, how far the user stack can grow
+pub const USER_STACK_LIMIT: usize = 0x80_0000; // 8 MiB
+/// the hard RLIMIT_STACK, the limit of a task can not be raised above it
+pub const USER_STACK_LIMIT_MAX: usize = 0x1_0000_0000; // 4 GiB
+/// the most bytes of a path or an argument from user space, with its `\0`
+pub const USER_STR_MAX: usize = 4096; // PATH_MAX
+/// the lowest address mmap picks when no hint is given
+pub const MMAP_BASE: usize = 0x20_0000_0000;
+/// where a position independent (ET_DYN) program is loaded
+pub const ELF_DYN_BASE: usize = 0x10_0000_0000;
+/// the load bias is moved up by up to this many pages
+pub const ELF_DYN_RANDOM_PAGES: usize = 1 << 16; // 256 MiB
+/// randomize the user address space layout,
+/// a process turns it off for itself with personality(ADDR_NO_RANDOMIZE)
+pub const ASLR: bool = true;
+/// the user stack top is moved down by up to this many pages
+pub const STACK_RANDOM_PAGES: usize = 1 << 16; // 256 MiB
+/// the mmap base is moved up by up to this many pages
+pub const MMAP_RANDOM_PAGES: usize = 1 << 20; // 4 GiB
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
+/// the kernel heap grows by at least this much when it runs out
+pub const KERNEL_HEAP_GROW_SIZE: usize = 0x10_0000; // 1 MiB
 pub const CLOCK_FREQ: usize = 12500000;
+/// stop the periodic timer tick while there is nothing to run
+pub const TICKLESS_IDLE: bool = true;
+/// page with Sv48 if the hart has it, and fall back to Sv39
+pub const PAGING_SV48: bool = true;
 pub const PAGE_SIZE: usize = 0x1000; // 4 KiB
 pub const PAGE_SIZE_BITS: usize = 0xc; // 2^12 = 4 KiB
 pub const MEMORY_END: usize = 0x8800_0000;
+/// size of the swap disk, the Makefile creates it
+pub const SWAP_SIZE: usize = 0x400_0000; // 64 MiB
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE; // trap.S trap handler context, is const in virtual memory
 
 pub const MMIO: &[(usize, usize)] = &[
-    (0x0010_0000, 0x00_2000), // VIRT_TEST/RTC  in virt machine
+    (0x0010_0000, 0x00_2000), // VIRT_TEST/RTC(goldfish) in virt machine
     (0x1000_1000, 0x00_1000), // Virtio Block in virt machine
+    (0x1000_2000, 0x00_1000), // Virtio Block for swap in virt machine
 ];
@@ -7,5 +7,9 @@ use lazy_static::lazy_static;
 pub type BlockDeviceImpl = virtio_blk::VirtIOBlock;
 
 lazy_static! {
-    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(BlockDeviceImpl::new());
+    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> =
+        Arc::new(BlockDeviceImpl::new(virtio_blk::VIRTIO0));
+    /// the second disk, holding the swapped out pages
+    pub static ref SWAP_DEVICE: Arc<dyn BlockDevice> =
+        Arc::new(BlockDeviceImpl::new(virtio_blk::VIRTIO1));
 }
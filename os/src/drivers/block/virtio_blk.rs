@@ -1,16 +1,17 @@
 use alloc::vec::Vec;
 use lazy_static::lazy_static;
+use riscv::register::satp;
 use virtio_drivers::{Hal, VirtIOBlk, VirtIOHeader};
 
 use super::BlockDevice;
+
 use crate::mm::{
-    frame_alloc, frame_dealloc, kernel_token, FrameTracker, PageTable, PhysAddr, PhysPageNum,
-    StepByOne, VirtAddr,
+    frame_alloc_contiguous, FrameTracker, PageTable, PhysAddr, PhysPageNum, VirtAddr,
 };
 use crate::sync::UPSafeCell;
 
-#[allow(unused)]
-const VIRTIO0: usize = 0x10001000;
+pub const VIRTIO0: usize = 0x10001000;
+pub const VIRTIO1: usize = 0x10002000;
 
 pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static, VirtioHal>>);
 
@@ -19,10 +20,11 @@ lazy_static! {
 }
 
 impl VirtIOBlock {
-    pub fn new() -> Self {
+    /// the device on the virtio-mmio transport at `base`
+    pub fn new(base: usize) -> Self {
         unsafe {
             Self(UPSafeCell::new(
-                VirtIOBlk::<VirtioHal>::new(&mut *(VIRTIO0 as *mut VirtIOHeader)).unwrap(),
+                VirtIOBlk::<VirtioHal>::new(&mut *(base as *mut VirtIOHeader)).unwrap(),
             ))
         }
     }
@@ -47,27 +49,23 @@ impl BlockDevice for VirtIOBlock {
 pub struct VirtioHal;
 
 impl Hal for VirtioHal {
+    /// 0 if out of memory, the driver fails the request
     fn dma_alloc(pages: usize) -> virtio_drivers::PhysAddr {
-        let mut ppn_base = PhysPageNum(0);
-        for i in 0..pages {
-            let frame = frame_alloc().unwrap();
-            if i == 0 {
-                ppn_base = frame.ppn;
-            }
-            assert_eq!(frame.ppn.0, ppn_base.0 + i);
-            QUEUE_FRAMES.exclusive_access().push(frame);
-        }
-        let pa: PhysAddr = ppn_base.into();
+        let frames = match frame_alloc_contiguous(pages, 1) {
+            Some(frames) => frames,
+            None => return 0,
+        };
+        let pa: PhysAddr = frames[0].ppn.into();
+        QUEUE_FRAMES.exclusive_access().extend(frames);
         pa.0
     }
 
     fn dma_dealloc(paddr: virtio_drivers::PhysAddr, pages: usize) -> i32 {
-        let pa = PhysAddr::from(paddr);
-        let mut ppn_base: PhysPageNum = pa.into();
-        for _ in 0..pages {
-            frame_dealloc(ppn_base);
-            ppn_base.step();
-        }
+        let ppn_base: PhysPageNum = PhysAddr::from(paddr).into();
+        // the frames are freed as they are dropped
+        QUEUE_FRAMES
+            .exclusive_access()
+            .retain(|frame| !(ppn_base.0..ppn_base.0 + pages).contains(&frame.ppn.0));
         0
     }
 
@@ -76,7 +74,9 @@ impl Hal for VirtioHal {
     }
 
     fn virt_to_phys(vaddr: virtio_drivers::VirtAddr) -> virtio_drivers::PhysAddr {
-        PageTable::from_token(kernel_token())
+        // the kernel space is always active in the kernel,
+        // and KERNEL_SPACE may be borrowed when swapping out for a frame
+        PageTable::from_token(satp::read().bits())
             .translate_va(VirtAddr::from(vaddr))
             .unwrap()
             .0
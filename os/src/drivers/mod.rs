@@ -1,3 +1,5 @@
 mod block;
+mod rtc;
 
-pub use block::BLOCK_DEVICE;
+pub use block::{BLOCK_DEVICE, SWAP_DEVICE};
+pub use rtc::RTC;
@@ -0,0 +1,33 @@
+use core::ptr::read_volatile;
+
+use super::Rtc;
+
+/// goldfish-rtc of the virt machine, right after VIRT_TEST
+const VIRT_RTC: usize = 0x101000;
+
+// registers, all 32 bits wide
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+pub struct GoldfishRtc {
+    base: usize,
+}
+
+impl GoldfishRtc {
+    pub fn new() -> Self {
+        Self { base: VIRT_RTC }
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+}
+
+impl Rtc for GoldfishRtc {
+    fn read_ns(&self) -> u64 {
+        // reading TIME_LOW latches TIME_HIGH, so the low word goes first
+        let low = self.read_reg(TIME_LOW) as u64;
+        let high = self.read_reg(TIME_HIGH) as u64;
+        high << 32 | low
+    }
+}
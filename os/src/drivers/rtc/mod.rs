@@ -0,0 +1,16 @@
+mod goldfish;
+
+use alloc::sync::Arc;
+use lazy_static::lazy_static;
+
+/// A real time clock
+pub trait Rtc: Send + Sync {
+    /// nanoseconds since the unix epoch
+    fn read_ns(&self) -> u64;
+}
+
+pub type RtcImpl = goldfish::GoldfishRtc;
+
+lazy_static! {
+    pub static ref RTC: Arc<dyn Rtc> = Arc::new(RtcImpl::new());
+}
@@ -2,7 +2,12 @@ use alloc::{sync::Arc, vec::Vec};
 use easy_fs::{EasyFileSystem, Inode};
 use lazy_static::lazy_static;
 
-use crate::{drivers::BLOCK_DEVICE, println, sync::UPSafeCell};
+use crate::{
+    drivers::BLOCK_DEVICE,
+    println,
+    sync::UPSafeCell,
+    task::{preempt_point, PreemptGuard},
+};
 
 use super::File;
 
@@ -28,19 +33,32 @@ impl OSInode {
 
     /// Read all data inside a inode into vector
     pub fn read_all(&self) -> Vec<u8> {
-        let mut inner = self.inner.exclusive_access();
         let mut buffer = [0u8; 512];
         let mut v: Vec<u8> = Vec::new();
         loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
+            let len = self.io_at_offset(|inode, offset| inode.read_at(offset, &mut buffer));
             if len == 0 {
                 break;
             }
-            inner.offset += len;
             v.extend_from_slice(&buffer[..len]);
         }
         v
     }
+
+    /// Do one read or write at the current offset and move the offset by its size.
+    /// The inner cell is not held during the I/O, which takes the easy-fs spin locks,
+    /// it runs in `with_fs` instead, so the offset is still updated atomically.
+    fn io_at_offset(&self, io: impl FnOnce(&Inode, usize) -> usize) -> usize {
+        with_fs(|| {
+            let (inode, offset) = {
+                let inner = self.inner.exclusive_access();
+                (inner.inode.clone(), inner.offset)
+            };
+            let size = io(&inode, offset);
+            self.inner.exclusive_access().offset += size;
+            size
+        })
+    }
 }
 
 impl File for OSInode {
@@ -52,30 +70,42 @@ impl File for OSInode {
         self.writable
     }
     fn read(&self, mut buf: crate::mm::UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
         let mut total_read_size = 0usize;
         for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
+            let read_size = self.io_at_offset(|inode, offset| inode.read_at(offset, *slice));
             if read_size == 0 {
                 break;
             }
-            inner.offset += read_size;
             total_read_size += read_size;
         }
         total_read_size
     }
 
     fn write(&self, buf: crate::mm::UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
         let mut total_write_size = 0usize;
         for slice in buf.buffers.iter() {
-            let write_size = inner.inode.write_at(inner.offset, *slice);
+            let write_size = self.io_at_offset(|inode, offset| inode.write_at(offset, *slice));
             assert_eq!(write_size, slice.len());
-            inner.offset += write_size;
             total_write_size += write_size;
         }
         total_write_size
     }
+
+    fn inode(&self) -> Option<Arc<Inode>> {
+        Some(self.inner.exclusive_access().inode.clone())
+    }
+}
+
+/// Run `f`, which calls into easy-fs, with kernel preemption disabled.
+/// easy-fs takes spin locks, a task preempted while holding one would
+/// leave the next task to take it spinning with preemption disabled forever.
+pub fn with_fs<T>(f: impl FnOnce() -> T) -> T {
+    let result = {
+        let _preempt = PreemptGuard::new();
+        f()
+    };
+    preempt_point();
+    result
 }
 
 lazy_static! {
@@ -87,7 +117,7 @@ lazy_static! {
 
 pub fn list_apps() {
     println!("/**** APPS ****/");
-    for app in ROOT_INODE.ls() {
+    for app in with_fs(|| ROOT_INODE.ls()) {
         println!("{}", app);
     }
     println!("/**** END ****/");
@@ -118,23 +148,25 @@ impl OpenFlags {
 
 pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
     let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            // clear size
-            inode.clear();
-            Some(Arc::new(OSInode::new(readable, writable, inode)))
-        } else {
-            // create file
-            ROOT_INODE
-                .create(name)
-                .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
-        }
-    } else {
-        ROOT_INODE.find(name).map(|inode| {
-            if flags.contains(OpenFlags::TRUNC) {
+    with_fs(|| {
+        if flags.contains(OpenFlags::CREATE) {
+            if let Some(inode) = ROOT_INODE.find(name) {
+                // clear size
                 inode.clear();
+                Some(Arc::new(OSInode::new(readable, writable, inode)))
+            } else {
+                // create file
+                ROOT_INODE
+                    .create(name)
+                    .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
             }
-            Arc::new(OSInode::new(readable, writable, inode))
-        })
-    }
+        } else {
+            ROOT_INODE.find(name).map(|inode| {
+                if flags.contains(OpenFlags::TRUNC) {
+                    inode.clear();
+                }
+                Arc::new(OSInode::new(readable, writable, inode))
+            })
+        }
+    })
 }
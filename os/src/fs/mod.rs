@@ -1,9 +1,12 @@
 mod inode;
 mod pipe;
 mod stdio;
+use alloc::sync::Arc;
+use easy_fs::Inode;
+
 use crate::mm::UserBuffer;
 
-pub use inode::{list_apps, open_file, OpenFlags};
+pub use inode::{list_apps, open_file, with_fs, OpenFlags};
 pub use pipe::make_pipe;
 pub use stdio::{Stdin, Stdout};
 
@@ -12,4 +15,8 @@ pub trait File: Send + Sync {
     fn writable(&self) -> bool;
     fn read(&self, buf: UserBuffer) -> usize;
     fn write(&self, buf: UserBuffer) -> usize;
+    /// the inode to map for mmap, None if the file can not be mapped
+    fn inode(&self) -> Option<Arc<Inode>> {
+        None
+    }
 }
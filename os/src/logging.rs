@@ -1,6 +1,13 @@
+use core::sync::atomic::{AtomicBool, Ordering};
 use log::{self, Level, LevelFilter, Log, Metadata, Record};
 
-use crate::println;
+use crate::{
+    println,
+    timer::{get_realtime_ns, get_time_us, DateTime},
+};
+
+/// whether the RTC can be read, the time since boot is logged before
+static REALTIME: AtomicBool = AtomicBool::new(false);
 
 #[derive(Debug, Clone, Copy)]
 enum LogColor {
@@ -40,9 +47,22 @@ impl Log for Logger {
         }
 
         let color = LogColor::from_level(record.level());
+        if REALTIME.load(Ordering::Relaxed) {
+            println!(
+                "\x1b[{}m[{}] [{}] - {}\x1b[0m",
+                color.val(),
+                DateTime::from_ns(get_realtime_ns()),
+                record.level(),
+                record.args()
+            );
+            return;
+        }
+        let us = get_time_us();
         println!(
-            "\x1b[{}m[{}] - {}\x1b[0m",
+            "\x1b[{}m[{:>5}.{:06}] [{}] - {}\x1b[0m",
             color.val(),
+            us / 1_000_000,
+            us % 1_000_000,
             record.level(),
             record.args()
         );
@@ -64,3 +84,10 @@ pub fn init() {
         _ => LevelFilter::Info,
     });
 }
+
+/// Log the date from the RTC from now on,
+/// once the heap is there for the driver and its MMIO is mapped.
+pub fn init_realtime() {
+    REALTIME.store(true, Ordering::Relaxed);
+    log::info!("[kernel] real time clock at {}", DateTime::from_ns(get_realtime_ns()));
+}
@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(panic_info_message)]
 #![feature(alloc_error_handler)]
+#![feature(asm_const)]
 
 extern crate alloc;
 mod config;
@@ -10,6 +11,7 @@ mod fs;
 mod lang_items;
 mod logging;
 mod mm;
+mod random;
 mod sbi;
 mod sync;
 mod syscall;
@@ -65,6 +67,7 @@ pub fn rust_main() -> ! {
     );
     error!("[kernel] .bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
     mm::init();
+    logging::init_realtime();
     task::add_initproc();
     println!("++++ after add_initproc!     ++++");
     trap::init();
@@ -1,12 +1,19 @@
 use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS};
 use core::fmt::{self, Debug, Formatter};
 
-use super::page_table::PageTableEntry;
+use super::page_table::{levels, PageTableEntry, MAX_LEVELS};
 
-const PA_WIDTH_SV39: usize = 56;
-const VA_WIDTH_SV39: usize = 39;
-const PPN_WIDTH_SV39: usize = PA_WIDTH_SV39 - PAGE_SIZE_BITS;
-const VPN_WIDTH_SV39: usize = VA_WIDTH_SV39 - PAGE_SIZE_BITS;
+/// the same for Sv39 and Sv48
+const PA_WIDTH: usize = 56;
+const PPN_WIDTH: usize = PA_WIDTH - PAGE_SIZE_BITS;
+
+/// 39 bits for Sv39 and 48 for Sv48
+pub fn va_width() -> usize {
+    PAGE_SIZE_BITS + 9 * levels()
+}
+fn vpn_width() -> usize {
+    va_width() - PAGE_SIZE_BITS
+}
 
 /// Definitions
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
@@ -48,22 +55,22 @@ impl Debug for PhysPageNum {
 }
 impl From<usize> for PhysAddr {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << PA_WIDTH_SV39) - 1))
+        Self(v & ((1 << PA_WIDTH) - 1))
     }
 }
 impl From<usize> for PhysPageNum {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << PPN_WIDTH_SV39) - 1))
+        Self(v & ((1 << PPN_WIDTH) - 1))
     }
 }
 impl From<usize> for VirtAddr {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << VA_WIDTH_SV39) - 1))
+        Self(v & ((1 << va_width()) - 1))
     }
 }
 impl From<usize> for VirtPageNum {
     fn from(v: usize) -> Self {
-        Self(v & ((1 << VPN_WIDTH_SV39) - 1))
+        Self(v & ((1 << vpn_width()) - 1))
     }
 }
 
@@ -79,8 +86,8 @@ impl From<PhysPageNum> for usize {
 }
 impl From<VirtAddr> for usize {
     fn from(v: VirtAddr) -> Self {
-        if v.0 >= (1 << (VA_WIDTH_SV39 - 1)) {
-            v.0 | (!((1 << VA_WIDTH_SV39) - 1))
+        if v.0 >= (1 << (va_width() - 1)) {
+            v.0 | (!((1 << va_width()) - 1))
         } else {
             v.0
         }
@@ -163,12 +170,12 @@ impl PhysPageNum {
 }
 
 impl VirtPageNum {
-    /// get page table entry indexes
-    /// 9 bits for each level
-    pub fn indexes(&self) -> [usize; 3] {
+    /// get page table entry indexes, from the root to the last level
+    /// 9 bits for each level, only the first `levels()` are used
+    pub fn indexes(&self) -> [usize; MAX_LEVELS] {
         let mut vpn = self.0;
-        let mut idx = [0_usize; 3];
-        for i in (0..3).rev() {
+        let mut idx = [0_usize; MAX_LEVELS];
+        for i in (0..levels()).rev() {
             idx[i] = vpn & ((1 << 9) - 1);
             vpn >>= 9;
         }
@@ -0,0 +1,106 @@
+//! Address space identifiers.
+//!
+//! Every user page table gets its own ASID in satp, so its TLB entries
+//! survive switching to the kernel and to other tasks. ASID 0 is the kernel's,
+//! and it is shared by the user spaces created when the ASIDs run out,
+//! whose entries trap.S flushes on every switch.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use lazy_static::lazy_static;
+
+use super::{VirtAddr, VirtPageNum};
+use crate::sync::UPSafeCell;
+
+/// A simple ASID allocator like the pid one,
+/// ASIDs from 1 to max, those after current are not allocated.
+struct AsidAllocator {
+    current: usize,
+    max: usize,
+    recycled: Vec<usize>,
+}
+
+impl AsidAllocator {
+    fn alloc(&mut self) -> Option<AsidHandle> {
+        if let Some(asid) = self.recycled.pop() {
+            Some(AsidHandle(asid))
+        } else if self.current <= self.max {
+            self.current += 1;
+            Some(AsidHandle(self.current - 1))
+        } else {
+            None
+        }
+    }
+
+    fn dealloc(&mut self, asid: usize) {
+        assert!(
+            0 < asid && asid < self.current,
+            "asid {} is not allocated",
+            asid
+        );
+        assert!(
+            self.recycled.iter().all(|&x| x != asid),
+            "asid {} is already recycled",
+            asid
+        );
+        self.recycled.push(asid);
+    }
+}
+
+lazy_static! {
+    static ref ASID_ALLOCATOR: UPSafeCell<AsidAllocator> = unsafe {
+        UPSafeCell::new(AsidAllocator {
+            current: 1,
+            max: 0,
+            recycled: Vec::new(),
+        })
+    };
+}
+
+/// The TLB entries tagged with the ASID are flushed when it is freed,
+/// so the next page table getting it starts clean.
+pub struct AsidHandle(pub usize);
+
+impl Drop for AsidHandle {
+    fn drop(&mut self) {
+        flush_asid(self.0);
+        ASID_ALLOCATOR.exclusive_access().dealloc(self.0);
+    }
+}
+
+/// Hand out ASIDs up to `max`, the largest one satp keeps.
+pub fn init(max: usize) {
+    ASID_ALLOCATOR.exclusive_access().max = max;
+}
+
+/// None if all the ASIDs are in use or the hart has none
+pub fn asid_alloc() -> Option<AsidHandle> {
+    ASID_ALLOCATOR.exclusive_access().alloc()
+}
+
+/// Flush the TLB entries of `vpn` in address space `asid`.
+/// The kernel maps its global pages with ASID 0, so for it the page
+/// is flushed in every address space.
+pub fn flush_page(asid: usize, vpn: VirtPageNum) {
+    if asid == 0 {
+        flush_global(vpn);
+        return;
+    }
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) VirtAddr::from(vpn).0, in(reg) asid);
+    }
+}
+
+/// Flush the TLB entries of `vpn` in every address space, the global ones too.
+pub fn flush_global(vpn: VirtPageNum) {
+    unsafe {
+        asm!("sfence.vma {}", in(reg) VirtAddr::from(vpn).0);
+    }
+}
+
+/// Flush the TLB entries of address space `asid`, the global ones stay.
+pub fn flush_asid(asid: usize) {
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid);
+    }
+}
@@ -1,10 +1,10 @@
-use alloc::vec::Vec;
+use alloc::{vec, vec::Vec};
 use core::fmt::{self, Debug, Formatter};
 use lazy_static::lazy_static;
 
 use crate::{config::MEMORY_END, mm::address::PhysAddr, println, sync::UPSafeCell};
 
-use super::address::PhysPageNum;
+use super::{address::PhysPageNum, swap::swap_out};
 
 /// 用于跟踪一个物理页的分配情况
 /// 通过 FrameTracker 可以实现 Drop trait 来自动释放物理页
@@ -18,6 +18,10 @@ impl Debug for FrameTracker {
     }
 }
 
+/// no frame is left, not even after swapping out pages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutOfMemory;
+
 impl FrameTracker {
     pub fn new(ppn: PhysPageNum) -> Self {
         // page cleaning
@@ -39,63 +43,199 @@ trait FrameAllocator {
     fn new() -> Self;
     fn alloc(&mut self) -> Option<PhysPageNum>;
     fn dealloc(&mut self, ppn: PhysPageNum);
+    /// `pages` consecutive frames, the first one aligned to `align` pages
+    fn alloc_contiguous(&mut self, pages: usize, align: usize) -> Option<PhysPageNum>;
 }
 
-/// 管理一段连续空闲内存
-pub struct StackFrameAllocator {
-    current: usize, //空闲内存的起始物理页号
-    end: usize,     //空闲内存的结束物理页号
-    recycled: Vec<usize>,
+/// A set of indices as a bitmap, with a summary bit for every non-empty word,
+/// so finding an index only scans the summary, 1/4096 of the indices.
+struct Bitmap {
+    words: Vec<u64>,
+    summary: Vec<u64>,
 }
 
-impl FrameAllocator for StackFrameAllocator {
-    fn new() -> Self {
+impl Bitmap {
+    fn new(bits: usize) -> Self {
+        let words = (bits + 63) / 64;
         Self {
-            current: 0,
-            end: 0,
-            recycled: Vec::new(),
+            words: vec![0; words],
+            summary: vec![0; (words + 63) / 64],
+        }
+    }
+    fn contains(&self, i: usize) -> bool {
+        self.words.get(i / 64).map_or(false, |word| word >> (i % 64) & 1 == 1)
+    }
+    fn insert(&mut self, i: usize) {
+        self.words[i / 64] |= 1 << (i % 64);
+        self.summary[i / 4096] |= 1 << (i / 64 % 64);
+    }
+    /// return false if `i` was not in the set
+    fn remove(&mut self, i: usize) -> bool {
+        if !self.contains(i) {
+            return false;
         }
+        self.words[i / 64] &= !(1 << (i % 64));
+        if self.words[i / 64] == 0 {
+            self.summary[i / 4096] &= !(1 << (i / 64 % 64));
+        }
+        true
+    }
+    fn first(&self) -> Option<usize> {
+        let (s, summary) = self
+            .summary
+            .iter()
+            .enumerate()
+            .find(|(_, summary)| **summary != 0)?;
+        let w = s * 64 + summary.trailing_zeros() as usize;
+        Some(w * 64 + self.words[w].trailing_zeros() as usize)
     }
+}
 
-    /// 优先从 recycled 中分配物理页号，如果 recycled 为空，则从 current 往上分配
-    fn alloc(&mut self) -> Option<PhysPageNum> {
-        if let Some(ppn) = self.recycled.pop() {
-            Some(ppn.into())
-        } else {
-            if self.current == self.end {
-                // 没有空闲内存
-                None
-            } else {
-                self.current += 1;
-                Some((self.current - 1).into())
+/// blocks of up to 2^(MAX_ORDER - 1) pages
+const MAX_ORDER: usize = 20;
+
+/// 伙伴系统分配器
+/// 空闲内存被划分为 2^order 页的块, 每块的起始物理页号按块大小对齐,
+/// 释放时与同样空闲的伙伴块合并.
+/// 空闲块和已分配的页都记录在位图中, 分配和释放只需常数时间.
+pub struct BuddyFrameAllocator {
+    /// 位图下标 0 对应的物理页号, 按最大的块对齐
+    base: usize,
+    /// 每个 order 的空闲块, 下标为 (ppn - base) >> order
+    free_blocks: Vec<Bitmap>,
+    /// 已分配的页, 用于检查重复释放
+    allocated: Bitmap,
+    total: usize,
+    allocated_count: usize,
+    peak: usize,
+}
+
+/// numbers of frames, for monitoring
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    /// the most frames ever allocated at the same time
+    pub peak: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// 将从 l 到 r 的内存空间初始化为空闲内存
+    pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.base = l.0 & !((1 << (MAX_ORDER - 1)) - 1);
+        let pages = r.0 - self.base;
+        self.free_blocks = (0..MAX_ORDER)
+            .map(|order| Bitmap::new((pages >> order) + 1))
+            .collect();
+        self.allocated = Bitmap::new(pages);
+        self.total = r.0 - l.0;
+        self.free_range(l.0, r.0);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            free: self.total - self.allocated_count,
+            peak: self.peak,
+        }
+    }
+
+    /// free `[start, end)` as the largest aligned blocks fitting in it
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let mut order = 0;
+            while order + 1 < MAX_ORDER
+                && start % (1 << (order + 1)) == 0
+                && start + (1 << (order + 1)) <= end
+            {
+                order += 1;
             }
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    /// free a block, merging it with its buddy as long as the buddy is free
+    fn free_block(&mut self, mut ppn: usize, mut order: usize) {
+        while order + 1 < MAX_ORDER
+            && self.free_blocks[order].remove(((ppn ^ (1 << order)) - self.base) >> order)
+        {
+            ppn &= !(1 << order);
+            order += 1;
+        }
+        self.free_blocks[order].insert((ppn - self.base) >> order);
+    }
+
+    /// a block of 2^order pages, split from a larger one if needed
+    fn alloc_block(&mut self, order: usize) -> Option<usize> {
+        let (from, index) = (order..MAX_ORDER)
+            .find_map(|o| self.free_blocks[o].first().map(|index| (o, index)))?;
+        self.free_blocks[from].remove(index);
+        let ppn = self.base + (index << from);
+        // the upper halves go back to the free blocks
+        for o in (order..from).rev() {
+            self.free_blocks[o].insert((ppn + (1 << o) - self.base) >> o);
+        }
+        Some(ppn)
+    }
+
+    fn mark_allocated(&mut self, ppn: usize, pages: usize) {
+        for ppn in ppn..ppn + pages {
+            self.allocated.insert(ppn - self.base);
+        }
+        self.allocated_count += pages;
+        self.peak = self.peak.max(self.allocated_count);
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn new() -> Self {
+        Self {
+            base: 0,
+            free_blocks: Vec::new(),
+            allocated: Bitmap::new(0),
+            total: 0,
+            allocated_count: 0,
+            peak: 0,
         }
     }
-    /// 将物理页号 ppn 放入 recycled 中
-    /// ppn 一定已经分配过（ppn < current）
+
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+        let ppn = self.alloc_block(0)?;
+        self.mark_allocated(ppn, 1);
+        Some(ppn.into())
+    }
+
     /// 一定不会重复释放同一个 ppn
     fn dealloc(&mut self, ppn: PhysPageNum) {
         let ppn = ppn.0;
         // validity check
-        if ppn >= self.current || self.recycled.iter().find(|&v| *v == ppn).is_some() {
+        if ppn < self.base || !self.allocated.remove(ppn - self.base) {
             panic!("Frame ppn={:#x} has not been allocated!", ppn);
         }
-        // recycle
-        self.recycled.push(ppn);
+        self.allocated_count -= 1;
+        self.free_block(ppn, 0);
     }
-}
 
-impl StackFrameAllocator {
-    /// 将从 l 到 r 的内存空间初始化为 StackFrameAllocator 管理的空闲内存
-    pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
-        self.current = l.0;
-        self.end = r.0;
+    /// 分配能容纳 pages 页且满足对齐的最小块, 多出的页立即释放
+    fn alloc_contiguous(&mut self, pages: usize, align: usize) -> Option<PhysPageNum> {
+        assert!(align.is_power_of_two(), "alignment {} is not a power of two", align);
+        let order = pages.max(align).next_power_of_two().trailing_zeros() as usize;
+        if pages == 0 || order >= MAX_ORDER {
+            return None;
+        }
+        let ppn = self.alloc_block(order)?;
+        self.free_range(ppn + pages, ppn + (1 << order));
+        self.mark_allocated(ppn, pages);
+        Some(ppn.into())
     }
 }
 
+type FrameAllocatorImpl = BuddyFrameAllocator;
+
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: UPSafeCell<StackFrameAllocator> =
-        unsafe { UPSafeCell::new(StackFrameAllocator::new()) };
+    pub static ref FRAME_ALLOCATOR: UPSafeCell<FrameAllocatorImpl> =
+        unsafe { UPSafeCell::new(FrameAllocatorImpl::new()) };
 }
 
 pub fn init_frame_allocator() {
@@ -110,10 +250,52 @@ pub fn init_frame_allocator() {
 }
 
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOCATOR
-        .exclusive_access()
-        .alloc()
-        .map(|ppn| FrameTracker::new(ppn))
+    loop {
+        if let Some(ppn) = FRAME_ALLOCATOR.exclusive_access().alloc() {
+            return Some(FrameTracker::new(ppn));
+        }
+        // 没有空闲页帧时换出一个用户页
+        if !swap_out() {
+            return None;
+        }
+    }
+}
+
+/// Allocate `pages` physically consecutive frames, the first one aligned to
+/// `align` pages, which is a power of two.
+/// Pages are swapped out until there is such a range, None if there is none left.
+pub fn frame_alloc_contiguous(pages: usize, align: usize) -> Option<Vec<FrameTracker>> {
+    loop {
+        let base = FRAME_ALLOCATOR
+            .exclusive_access()
+            .alloc_contiguous(pages, align);
+        if let Some(base) = base {
+            return Some(
+                (base.0..base.0 + pages)
+                    .map(|ppn| FrameTracker::new(ppn.into()))
+                    .collect(),
+            );
+        }
+        if !swap_out() {
+            return None;
+        }
+    }
+}
+
+/// `pages` consecutive frames for the kernel heap, aligned to `pages`.
+/// They are not cleared and never freed, and no user page is swapped out
+/// for them, as the heap may grow in the middle of swapping.
+pub fn frame_alloc_for_heap(pages: usize) -> Option<PhysPageNum> {
+    let mut allocator = FRAME_ALLOCATOR.exclusive_access();
+    // no frames before init_frame_allocator
+    if allocator.free_blocks.is_empty() {
+        return None;
+    }
+    allocator.alloc_contiguous(pages, pages)
+}
+
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.exclusive_access().stats()
 }
 
 /// Private, 由 FrameTracker 的生命周期来管理物理帧的释放
@@ -136,5 +318,16 @@ pub fn frame_allocator_test() {
         v.push(frame);
     }
     drop(v);
+    // consecutive even with single frames freed in between
+    let singles: Vec<FrameTracker> = (0..5).map(|_| frame_alloc().unwrap()).collect();
+    let frames = frame_alloc_contiguous(5, 8).unwrap();
+    assert_eq!(frames[0].ppn.0 % 8, 0);
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame.ppn.0, frames[0].ppn.0 + i);
+    }
+    let free = frame_stats().free;
+    drop(singles);
+    drop(frames);
+    assert_eq!(frame_stats().free, free + 10);
     println!("frame_allocator_test passed!");
 }
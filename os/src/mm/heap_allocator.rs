@@ -1,9 +1,70 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use buddy_system_allocator::LockedHeap;
 
-use crate::{config::KERNEL_HEAP_SIZE, println};
+use super::{address::PhysAddr, frame_allocator::frame_alloc_for_heap};
+use crate::{
+    config::{KERNEL_HEAP_GROW_SIZE, KERNEL_HEAP_SIZE, PAGE_SIZE},
+    println,
+    sync::IntrGuard,
+};
+
+/// The heap is locked by a spin lock,
+/// so mask interrupts while holding it, as the kernel trap handler allocates too.
+/// It starts with HEAP_SPACE and grows with frames when that runs out.
+struct KernelHeap(LockedHeap);
+
+/// the most bytes ever allocated at the same time
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _intr = IntrGuard::new();
+        loop {
+            let ptr = self.0.alloc(layout);
+            if !ptr.is_null() {
+                PEAK.fetch_max(self.0.lock().stats_alloc_actual(), Ordering::Relaxed);
+                return ptr;
+            }
+            if !self.grow(layout) {
+                return ptr;
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _intr = IntrGuard::new();
+        self.0.dealloc(ptr, layout)
+    }
+}
+
+impl KernelHeap {
+    /// Add frames with room for `layout` to the heap, false if there are none.
+    /// The frames stay in the heap for good.
+    fn grow(&self, layout: Layout) -> bool {
+        // the buddy heap only makes a block of a range aligned to its size
+        let size = layout
+            .size()
+            .max(layout.align())
+            .next_power_of_two()
+            .max(KERNEL_HEAP_GROW_SIZE);
+        let pages = size / PAGE_SIZE;
+        match frame_alloc_for_heap(pages) {
+            Some(ppn) => {
+                let start = PhysAddr::from(ppn).0;
+                unsafe { self.0.lock().add_to_heap(start, start + size) };
+                true
+            }
+            None => false,
+        }
+    }
+}
 
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
+static HEAP_ALLOCATOR: KernelHeap = KernelHeap(LockedHeap::empty());
 
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
@@ -15,11 +76,31 @@ static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 pub fn init_heap() {
     unsafe {
         HEAP_ALLOCATOR
+        .0
         .lock()
         .init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
     }
 }
 
+/// bytes of the kernel heap, for monitoring
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// HEAP_SPACE and the frames added since
+    pub total: usize,
+    /// allocated, including the rounding up to a block
+    pub used: usize,
+    pub peak: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let _intr = IntrGuard::new();
+    let heap = HEAP_ALLOCATOR.0.lock();
+    HeapStats {
+        total: heap.stats_total_bytes(),
+        used: heap.stats_alloc_actual(),
+        peak: PEAK.load(Ordering::Relaxed),
+    }
+}
 
 #[allow(unused)]
 pub fn heap_test() {
@@ -43,5 +124,12 @@ pub fn heap_test() {
     }
     assert!(bss_range.contains(&(v.as_ptr() as usize)));
     drop(v);
+    // more than HEAP_SPACE, so it comes from frames
+    let total = heap_stats().total;
+    let big: Vec<u8> = alloc::vec![1; KERNEL_HEAP_SIZE];
+    assert!(!bss_range.contains(&(big.as_ptr() as usize)));
+    assert!(big.iter().all(|&b| b == 1));
+    assert!(heap_stats().total > total);
+    drop(big);
     println!("heap_test passed!");
-}
\ No newline at end of file
+}
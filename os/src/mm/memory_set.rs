@@ -1,18 +1,27 @@
 use core::arch::asm;
 
 use super::{
-    address::{PhysAddr, PhysPageNum, StepByOne, VPNRange, VirtAddr, VirtPageNum},
-    frame_allocator::{frame_alloc, FrameTracker},
-    page_table::{PTEFlags, PageTable, PageTableEntry},
+    address::{va_width, PhysAddr, PhysPageNum, StepByOne, VPNRange, VirtAddr, VirtPageNum},
+    frame_allocator::{frame_alloc, FrameTracker, OutOfMemory},
+    page_table::{level_pages, levels, PTEFlags, PageTable, PageTableEntry},
+    shared::{queue_write_back, SharedFrames},
+    swap,
 };
 use crate::{
-    config::{MEMORY_END, MMIO, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE},
+    config::{
+        ELF_DYN_BASE, ELF_DYN_RANDOM_PAGES, MEMORY_END, MMAP_BASE, MMAP_RANDOM_PAGES, MMIO,
+        PAGE_SIZE, STACK_RANDOM_PAGES, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_LIMIT,
+        USER_STACK_LIMIT_MAX, USER_STACK_SIZE,
+    },
     println,
+    random::random_below,
     sync::UPSafeCell,
+    task::{preempt_point, PreemptGuard},
 };
 use alloc::{collections::btree_map::BTreeMap, sync::Arc, vec::Vec};
 use lazy_static::lazy_static;
 use riscv::register::satp;
+use xmas_elf::{header, program};
 
 extern "C" {
     fn stext();
@@ -27,20 +36,345 @@ extern "C" {
     fn strampoline();
 }
 
+/// how far below sp an access may grow the stack, as for a push
+const STACK_SP_SLACK: usize = PAGE_SIZE;
+
 pub struct MapArea {
     vpn_range: VPNRange,
     // FrameTracker for the used frames
-    // the lifetime of the frames belongs to here
+    // the lifetime of the frames belongs to here,
+    // except for lazy areas whose frames belong to the swap manager
     data_frames: BTreeMap<VirtPageNum, FrameTracker>,
     map_type: MapType,
     map_perm: MapPermission,
+    // the frames of a shared or copy-on-write area, frame `i` of them
+    // mapped at `shared_base + i`, wrapping around for a file offset
+    shared: Option<Arc<SharedFrames>>,
+    shared_base: VirtPageNum,
+    // whether the area may be made writable,
+    // not a shared mapping of a file opened read-only
+    may_write: bool,
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
-/// map type for memory set: identical or framed
+/// map type for memory set: identical, framed, lazy, shared or copy-on-write
 pub enum MapType {
-    Identical, // 恒等映射
-    Framed,    // 随机映射
+    Identical,   // 恒等映射
+    Framed,      // 随机映射
+    Lazy,        // 随机映射, 首次访问缺页时才分配页帧, 可被换出
+    Shared,      // 映射到共享的页帧, 页帧不属于该区域
+    CopyOnWrite, // 先只读映射共享的页帧, 写时复制到该区域自己的页帧
+}
+
+/// reasons for refusing to load an elf image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElfLoadError {
+    /// the file can not be parsed as elf, with the reason from the parser
+    Malformed(&'static str),
+    BadMagic,
+    /// not an executable for RISC-V
+    WrongMachine,
+    /// not an ET_EXEC or ET_DYN file
+    NotExecutable,
+    /// a segment is out of user space or hits TrapContext/trampoline
+    BadSegment,
+    /// two loadable segments share a page
+    SegmentOverlap,
+    /// a relocation the kernel does not handle, or out of the image
+    BadRelocation,
+    /// the program needs an interpreter, and it is missing or not usable
+    BadInterpreter,
+    /// no frames for the image
+    OutOfMemory,
+    /// the arguments do not fit in the stack within its limit
+    ArgsTooBig,
+}
+
+impl From<OutOfMemory> for ElfLoadError {
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+/// for `write_user`, which only writes the arguments on the stack
+impl From<FaultError> for ElfLoadError {
+    fn from(err: FaultError) -> Self {
+        match err {
+            FaultError::Invalid => Self::ArgsTooBig,
+            FaultError::OutOfMemory => Self::OutOfMemory,
+        }
+    }
+}
+
+// entries of the auxiliary vector put after argv on the user stack
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_BASE: usize = 7;
+const AT_ENTRY: usize = 9;
+
+// dynamic section tags and relocation types the loader looks at
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const R_RISCV_NONE: u64 = 0;
+const R_RISCV_RELATIVE: u64 = 3;
+
+/// An elf file checked to be loadable, addresses are before the load bias
+struct ElfImage<'a> {
+    elf: xmas_elf::ElfFile<'a>,
+    /// ET_DYN, loaded at a bias chosen by the kernel
+    relocatable: bool,
+    /// the loadable segments, (start_va, end_va, perm, file range), sorted
+    segments: Vec<(usize, usize, MapPermission, usize, usize)>,
+    /// R_RISCV_RELATIVE relocations: (where, addend)
+    relative: Vec<(usize, u64)>,
+    /// has relocations needing symbols, only a dynamic loader can do them
+    symbolic: bool,
+    /// the path of the dynamic loader in PT_INTERP
+    interp: Option<&'a str>,
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    let bytes = data.get(at..at.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl<'a> ElfImage<'a> {
+    fn parse(elf_data: &'a [u8]) -> Result<Self, ElfLoadError> {
+        let elf = xmas_elf::ElfFile::new(elf_data).map_err(ElfLoadError::Malformed)?;
+        let elf_header = elf.header;
+        let magic = elf_header.pt1.magic;
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            return Err(ElfLoadError::BadMagic);
+        }
+        if elf_header.pt1.class() != header::Class::SixtyFour {
+            return Err(ElfLoadError::Malformed("not a 64-bit elf"));
+        }
+        if elf_header.pt2.machine().as_machine() != header::Machine::RISC_V {
+            return Err(ElfLoadError::WrongMachine);
+        }
+        let relocatable = match elf_header.pt2.type_().as_type() {
+            header::Type::Executable => false,
+            header::Type::SharedObject => true,
+            _ => return Err(ElfLoadError::NotExecutable),
+        };
+        // xmas_elf slices and casts the program header table without
+        // checking it against the input, so do it here
+        let ph_count = elf_header.pt2.ph_count() as usize;
+        let ph_offset = elf_header.pt2.ph_offset() as usize;
+        let ph_entry_size = elf_header.pt2.ph_entry_size() as usize;
+        if ph_count == 0 {
+            return Err(ElfLoadError::Malformed("no program headers"));
+        }
+        if ph_entry_size != core::mem::size_of::<program::ProgramHeader64>()
+            || ph_offset
+                .checked_add(ph_count * ph_entry_size)
+                .map_or(true, |end| end > elf_data.len())
+            || (elf_data.as_ptr() as usize + ph_offset) % core::mem::align_of::<u64>() != 0
+        {
+            return Err(ElfLoadError::Malformed("bad program header table"));
+        }
+
+        let mut segments: Vec<(usize, usize, MapPermission, usize, usize)> = Vec::new();
+        let mut dynamic = None;
+        let mut interp = None;
+        for i in 0..ph_count {
+            let ph = elf
+                .program_header(i as u16)
+                .map_err(ElfLoadError::Malformed)?;
+            let offset = ph.offset() as usize;
+            let file_end = offset
+                .checked_add(ph.file_size() as usize)
+                .filter(|&end| end <= elf_data.len())
+                .ok_or(ElfLoadError::Malformed("segment data out of file"))?;
+            match ph.get_type().map_err(ElfLoadError::Malformed)? {
+                program::Type::Load => {}
+                program::Type::Dynamic => {
+                    dynamic = Some(&elf_data[offset..file_end]);
+                    continue;
+                }
+                program::Type::Interp => {
+                    let path = &elf_data[offset..file_end];
+                    let path = path.strip_suffix(&[0]).unwrap_or(path);
+                    interp = Some(
+                        core::str::from_utf8(path)
+                            .map_err(|_| ElfLoadError::Malformed("bad interpreter path"))?,
+                    );
+                    continue;
+                }
+                _ => continue,
+            }
+            if ph.file_size() > ph.mem_size() {
+                return Err(ElfLoadError::Malformed("segment file size exceeds memory size"));
+            }
+            let start = ph.virtual_addr() as usize;
+            let end = start
+                .checked_add(ph.mem_size() as usize)
+                .ok_or(ElfLoadError::BadSegment)?;
+            // an ET_DYN image is checked again after the bias is added
+            if !is_user_range(start, end) {
+                return Err(ElfLoadError::BadSegment);
+            }
+            // file data is copied from the page start, so it must agree on the offset
+            if ph.file_size() > 0 && VirtAddr::from(start).page_offset() != 0 {
+                return Err(ElfLoadError::BadSegment);
+            }
+            let mut map_perm = MapPermission::U;
+            let ph_flags = ph.flags();
+            if ph_flags.is_read() {
+                map_perm |= MapPermission::R;
+            }
+            if ph_flags.is_write() {
+                map_perm |= MapPermission::W;
+            }
+            if ph_flags.is_execute() {
+                map_perm |= MapPermission::X;
+            }
+            segments.push((start, end, map_perm, offset, file_end));
+        }
+        if segments.is_empty() {
+            return Err(ElfLoadError::Malformed("no loadable segments"));
+        }
+        // segments are mapped page by page, so they must not share a page
+        segments.sort_by_key(|seg| seg.0);
+        for pair in segments.windows(2) {
+            let prev_end = VirtAddr::from(pair[0].1).ceil();
+            let next_start = VirtAddr::from(pair[1].0).floor();
+            if prev_end > next_start {
+                return Err(ElfLoadError::SegmentOverlap);
+            }
+        }
+        let mut image = Self {
+            elf,
+            relocatable,
+            segments,
+            relative: Vec::new(),
+            symbolic: false,
+            interp,
+        };
+        if let Some(dynamic) = dynamic {
+            image.parse_relocations(dynamic)?;
+        }
+        Ok(image)
+    }
+
+    /// Collect the relocations listed in the PT_DYNAMIC segment `dynamic`.
+    fn parse_relocations(&mut self, dynamic: &[u8]) -> Result<(), ElfLoadError> {
+        let (mut rela, mut rela_size, mut rela_entry_size) = (None, 0, 24);
+        for entry in dynamic.chunks_exact(16) {
+            let tag = read_u64(entry, 0).unwrap();
+            let value = read_u64(entry, 8).unwrap();
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(value as usize),
+                DT_RELASZ => rela_size = value as usize,
+                DT_RELAENT => rela_entry_size = value as usize,
+                // RISC-V only uses rela
+                DT_REL => return Err(ElfLoadError::BadRelocation),
+                _ => {}
+            }
+        }
+        let rela = match rela {
+            Some(rela) => rela,
+            None => return Ok(()),
+        };
+        if rela_entry_size != 24 {
+            return Err(ElfLoadError::BadRelocation);
+        }
+        let table = self
+            .file_offset(rela)
+            .and_then(|offset| self.elf.input.get(offset..offset.checked_add(rela_size)?))
+            .ok_or(ElfLoadError::BadRelocation)?;
+        for entry in table.chunks_exact(24) {
+            let offset = read_u64(entry, 0).unwrap() as usize;
+            let info = read_u64(entry, 8).unwrap();
+            let addend = read_u64(entry, 16).unwrap();
+            match info & 0xffff_ffff {
+                R_RISCV_NONE => {}
+                R_RISCV_RELATIVE => {
+                    // the word has to be in the image, wherever it is loaded
+                    let in_image = self.segments.iter().any(|&(start, end, ..)| {
+                        start <= offset && offset.checked_add(8).map_or(false, |e| e <= end)
+                    });
+                    if !in_image {
+                        return Err(ElfLoadError::BadRelocation);
+                    }
+                    self.relative.push((offset, addend));
+                }
+                _ => self.symbolic = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// where the file data of `va` is
+    fn file_offset(&self, va: usize) -> Option<usize> {
+        self.segments
+            .iter()
+            .find(|&&(start, _, _, offset, file_end)| start <= va && va - start < file_end - offset)
+            .map(|&(start, _, _, offset, _)| offset + va - start)
+    }
+
+    /// the end of the last segment
+    fn end(&self) -> usize {
+        self.segments.last().unwrap().1
+    }
+
+    /// where the program header table is mapped, if it is in a segment
+    fn phdr_va(&self) -> Option<usize> {
+        let ph_offset = self.elf.header.pt2.ph_offset() as usize;
+        let ph_end = ph_offset
+            + self.elf.header.pt2.ph_count() as usize * self.elf.header.pt2.ph_entry_size() as usize;
+        self.segments
+            .iter()
+            .find(|&&(_, _, _, offset, file_end)| offset <= ph_offset && ph_end <= file_end)
+            .map(|&(start, _, _, offset, _)| start + ph_offset - offset)
+    }
+}
+
+/// The dynamic loader an elf file asks for in PT_INTERP
+pub fn elf_interpreter(elf_data: &[u8]) -> Result<Option<&str>, ElfLoadError> {
+    ElfImage::parse(elf_data).map(|image| image.interp)
+}
+
+/// why a page fault could not be handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultError {
+    /// no area allows the access, a real fault
+    Invalid,
+    OutOfMemory,
+}
+
+impl From<OutOfMemory> for FaultError {
+    fn from(_: OutOfMemory) -> Self {
+        Self::OutOfMemory
+    }
+}
+
+/// where a page of a forked child is copied from
+#[derive(Clone, Copy)]
+enum PageSource {
+    Frame(PhysPageNum),
+    Slot(usize),
+}
+
+/// The layout of a user space and where its pages are, taken by `snapshot`.
+/// The pages must stay there until `from_snapshot` has copied them:
+/// pinned, and not unmapped or swapped in by their task.
+pub struct MemorySnapshot {
+    stack_top: usize,
+    stack_limit: usize,
+    stack_limit_max: usize,
+    mmap_base: usize,
+    heap_start: VirtPageNum,
+    heap_end: VirtPageNum,
+    /// the areas with their pages to copy
+    areas: Vec<(MapArea, Vec<(VirtPageNum, PageSource)>)>,
 }
 
 bitflags! {
@@ -66,6 +400,31 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            shared: None,
+            shared_base: start_vpn,
+            may_write: true,
+        }
+    }
+
+    /// a shared or copy-on-write area mapping `pages` of `frames`
+    /// from frame `offset` at `start_va`
+    pub fn new_shared(
+        start_va: VirtAddr,
+        pages: usize,
+        frames: Arc<SharedFrames>,
+        offset: usize,
+        map_type: MapType,
+        map_perm: MapPermission,
+    ) -> Self {
+        let start_vpn = start_va.floor();
+        Self {
+            vpn_range: VPNRange::new(start_vpn, VirtPageNum(start_vpn.0 + pages)),
+            data_frames: BTreeMap::new(),
+            map_type,
+            map_perm,
+            shared: Some(frames),
+            shared_base: VirtPageNum(start_vpn.0.wrapping_sub(offset)),
+            may_write: true,
         }
     }
 
@@ -75,38 +434,257 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type: another.map_type,
             map_perm: another.map_perm,
+            shared: another.shared.clone(),
+            shared_base: another.shared_base,
+            may_write: another.may_write,
         }
     }
 
     /// allocate frames for the given vpn
-    /// and map the vpn and ppn to the page table and self.data_frames
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
+    /// and map the vpn and ppn to the page table and self.data_frames,
+    /// nothing is mapped if out of memory
+    pub fn map_one(
+        &mut self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+    ) -> Result<(), OutOfMemory> {
         let ppn: PhysPageNum;
         match self.map_type {
             MapType::Identical => {
                 ppn = PhysPageNum(vpn.0);
             }
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
-                ppn = frame.ppn;
+                let frame = frame_alloc().ok_or(OutOfMemory)?;
+                page_table.map(vpn, frame.ppn, self.pte_flags())?;
                 self.data_frames.insert(vpn, frame);
+                return Ok(());
+            }
+            MapType::Lazy => {
+                let frame = frame_alloc().ok_or(OutOfMemory)?;
+                return self.map_swappable(page_table, vpn, frame, None, false);
             }
+            MapType::Shared => {
+                // pages of a file not read in yet are mapped on page faults
+                match self.shared_frames().present(self.shared_index(vpn)) {
+                    Some(shared_ppn) => ppn = shared_ppn,
+                    None => return Ok(()),
+                }
+            }
+            MapType::CopyOnWrite => unreachable!("copy-on-write pages are mapped on page faults"),
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits()).unwrap();
-        page_table.map(vpn, ppn, pte_flags);
+        page_table.map(vpn, ppn, self.pte_flags())
+    }
+    fn pte_flags(&self) -> PTEFlags {
+        let flags = PTEFlags::from_bits(self.map_perm.bits()).unwrap();
+        // identical areas are only in the kernel space, those of the kernel image and
+        // the physical memory are global to all address spaces, as user space can not
+        // map there (see is_user_range); not the MMIO ones at user addresses, nor the
+        // kernel stacks, which are where user spaces have their TrapContext
+        if self.map_type == MapType::Identical
+            && VirtAddr::from(self.vpn_range.get_start()).0 >= stext as usize
+        {
+            flags | PTEFlags::G
+        } else {
+            flags
+        }
+    }
+    fn shared_frames(&self) -> &SharedFrames {
+        self.shared.as_ref().unwrap()
+    }
+    /// the index in the shared frames of page `vpn`
+    fn shared_index(&self, vpn: VirtPageNum) -> usize {
+        vpn.0.wrapping_sub(self.shared_base.0)
+    }
+    /// Map the page of a shared area from its frames, which `file_page` has
+    /// read in, Invalid if it is past the end of the file.
+    fn map_shared(&self, page_table: &mut PageTable, vpn: VirtPageNum) -> Result<(), FaultError> {
+        let ppn = self
+            .shared_frames()
+            .present(self.shared_index(vpn))
+            .ok_or(FaultError::Invalid)?;
+        page_table.map(vpn, ppn, self.pte_flags())?;
+        Ok(())
+    }
+    /// Handle a fault on a page of a copy-on-write area: a read maps the
+    /// shared frame read-only, a write copies it to a frame of the area.
+    /// Invalid if there is nothing to do or the page is past the end of the file.
+    fn copy_on_write(
+        &mut self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+        access: MapPermission,
+    ) -> Result<(), FaultError> {
+        let valid = page_table.translate(vpn).map_or(false, |pte| pte.is_valid());
+        let write = access.contains(MapPermission::W);
+        if valid && (!write || self.data_frames.contains_key(&vpn)) {
+            return Err(FaultError::Invalid);
+        }
+        let src = self
+            .shared_frames()
+            .present(self.shared_index(vpn))
+            .ok_or(FaultError::Invalid)?;
+        if !write {
+            page_table.map(vpn, src, self.pte_flags() - PTEFlags::W)?;
+            return Ok(());
+        }
+        let frame = frame_alloc().ok_or(OutOfMemory)?;
+        frame
+            .ppn
+            .get_bytes_array()
+            .copy_from_slice(src.get_bytes_array());
+        if valid {
+            page_table.unmap(vpn);
+        }
+        // the page tables are there if the page was mapped
+        page_table.map(vpn, frame.ppn, self.pte_flags())?;
+        self.data_frames.insert(vpn, frame);
+        Ok(())
+    }
+    /// Change the permission of the area, the mapped pages get the new flags
+    /// except the write permission of pages still to be copied on write.
+    pub fn set_perm(&mut self, page_table: &mut PageTable, perm: MapPermission) {
+        self.map_perm = perm;
+        for vpn in self.vpn_range {
+            let mut flags = self.pte_flags();
+            if self.map_type == MapType::CopyOnWrite && !self.data_frames.contains_key(&vpn) {
+                flags -= PTEFlags::W;
+            }
+            match page_table.find_pte(vpn) {
+                Some(pte) if pte.is_valid() => pte.set_perm(flags),
+                _ => continue,
+            }
+            page_table.flush(vpn);
+        }
+    }
+    /// queue the page to be written back to the file of a shared area if it is dirty
+    fn write_back(&self, page_table: &mut PageTable, vpn: VirtPageNum) {
+        let pte = match page_table.find_pte(vpn) {
+            Some(pte) if pte.is_valid() && pte.dirty() => pte,
+            _ => return,
+        };
+        queue_write_back(self.shared.as_ref().unwrap(), self.shared_index(vpn));
+        pte.clear_dirty();
+        page_table.flush(vpn);
+    }
+    /// Map a page of a lazy area to `frame` and hand the frame to the swap manager,
+    /// `slot` is where the data came from and `dirty` is false if the data is
+    /// the same as there or all zero.
+    fn map_swappable(
+        &self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+        frame: FrameTracker,
+        slot: Option<usize>,
+        dirty: bool,
+    ) -> Result<(), OutOfMemory> {
+        let mut pte_flags = self.pte_flags();
+        if dirty {
+            pte_flags |= PTEFlags::A | PTEFlags::D;
+        }
+        // mapped before tracked, the allocations for the page table
+        // may swap out pages
+        page_table.map(vpn, frame.ppn, pte_flags)?;
+        swap::track(page_table.token(), vpn, frame, slot);
+        Ok(())
+    }
+    /// bring a page of a lazy area back from `slot`,
+    /// it stays swapped out if out of memory
+    fn swap_in(
+        &self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+        slot: usize,
+    ) -> Result<(), OutOfMemory> {
+        let frame = frame_alloc().ok_or(OutOfMemory)?;
+        swap::read_slot(slot, frame.ppn);
+        self.map_swappable(page_table, vpn, frame, Some(slot), false)
     }
-    #[allow(unused)]
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        if self.map_type == MapType::Framed {
-            self.data_frames.remove(&vpn); // deallocate the frame
+        match self.map_type {
+            MapType::Identical => {
+                // the whole huge page went with its first page
+                if !page_table.translate(vpn).map_or(false, |pte| pte.is_valid()) {
+                    return;
+                }
+            }
+            MapType::Framed => {
+                self.data_frames.remove(&vpn); // deallocate the frame
+            }
+            MapType::Lazy => {
+                let pte = page_table.translate(vpn);
+                if let Some(slot) = pte.and_then(|pte| pte.swap_slot()) {
+                    swap::free_slot(slot);
+                    *page_table.find_pte(vpn).unwrap() = PageTableEntry::empty();
+                }
+                // never touched or swapped out, nothing mapped
+                if !pte.map_or(false, |pte| pte.is_valid()) {
+                    return;
+                }
+                page_table.unmap(vpn);
+                swap::untrack(page_table.token(), vpn);
+                return;
+            }
+            MapType::Shared | MapType::CopyOnWrite => {
+                if !page_table.translate(vpn).map_or(false, |pte| pte.is_valid()) {
+                    return;
+                }
+                if self.map_type == MapType::Shared {
+                    self.write_back(page_table, vpn);
+                }
+                self.data_frames.remove(&vpn);
+            }
         }
         page_table.unmap(vpn);
     }
-    /// allocate the whole area's frames and map them
-    pub fn map(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
-            self.map_one(page_table, vpn);
+    /// allocate the whole area's frames and map them,
+    /// lazy and copy-on-write areas get their frames on page faults instead
+    pub fn map(&mut self, page_table: &mut PageTable) -> Result<(), OutOfMemory> {
+        if matches!(self.map_type, MapType::Lazy | MapType::CopyOnWrite) {
+            return Ok(());
+        }
+        self.map_range(page_table, self.vpn_range.get_start(), self.vpn_range.get_end())
+    }
+    /// map the pages in `[start, end)`, none of them if out of memory
+    fn map_range(
+        &mut self,
+        page_table: &mut PageTable,
+        start: VirtPageNum,
+        end: VirtPageNum,
+    ) -> Result<(), OutOfMemory> {
+        let mut vpn = start;
+        while vpn < end {
+            let mapped = match self.map_type {
+                MapType::Identical => self.map_identical(page_table, vpn, end),
+                _ => self.map_one(page_table, vpn).map(|_| 1),
+            };
+            match mapped {
+                Ok(pages) => vpn = VirtPageNum(vpn.0 + pages),
+                Err(err) => {
+                    for mapped in VPNRange::new(start, vpn) {
+                        self.unmap_one(page_table, mapped);
+                    }
+                    return Err(err);
+                }
+            }
         }
+        Ok(())
+    }
+    /// Map the pages from `vpn` to themselves with the largest leaf
+    /// aligned there and ending before `end`, return the number of pages.
+    fn map_identical(
+        &self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+        end: VirtPageNum,
+    ) -> Result<usize, OutOfMemory> {
+        let level = (0..levels())
+            .find(|&level| {
+                let pages = level_pages(level);
+                vpn.0 % pages == 0 && vpn.0 + pages <= end.0
+            })
+            .unwrap();
+        page_table.map_huge(vpn, PhysPageNum(vpn.0), self.pte_flags(), level)?;
+        Ok(level_pages(level))
     }
     #[allow(unused)]
     pub fn unmap(&mut self, page_table: &mut PageTable) {
@@ -114,6 +692,52 @@ impl MapArea {
             self.unmap_one(page_table, vpn);
         }
     }
+    /// map the pages from the end of the area up to `new_end`,
+    /// the area is left as it was if out of memory
+    pub fn append_to(
+        &mut self,
+        page_table: &mut PageTable,
+        new_end: VirtPageNum,
+    ) -> Result<(), OutOfMemory> {
+        let (start, end) = (self.vpn_range.get_start(), self.vpn_range.get_end());
+        if self.map_type != MapType::Lazy {
+            self.map_range(page_table, end, new_end)?;
+        }
+        self.vpn_range = VPNRange::new(start, new_end);
+        Ok(())
+    }
+    /// unmap the pages from `new_end` to the end of the area
+    pub fn shrink_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) {
+        let (start, end) = (self.vpn_range.get_start(), self.vpn_range.get_end());
+        for vpn in VPNRange::new(new_end, end) {
+            self.unmap_one(page_table, vpn);
+        }
+        self.vpn_range = VPNRange::new(start, new_end);
+    }
+    /// move the start of a lazy area down to `new_start`, nothing to map
+    pub fn extend_down_to(&mut self, new_start: VirtPageNum) {
+        assert_eq!(self.map_type, MapType::Lazy);
+        self.vpn_range = VPNRange::new(new_start, self.vpn_range.get_end());
+    }
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.vpn_range.get_start() <= vpn && vpn < self.vpn_range.get_end()
+    }
+    /// Split the area at `at`, keep `[start, at)` and return `[at, end)`
+    /// with the frames of its pages.
+    pub fn split_off(&mut self, at: VirtPageNum) -> MapArea {
+        let (start, end) = (self.vpn_range.get_start(), self.vpn_range.get_end());
+        assert!(start < at && at < end, "split {:?} out of the area", at);
+        self.vpn_range = VPNRange::new(start, at);
+        Self {
+            vpn_range: VPNRange::new(at, end),
+            data_frames: self.data_frames.split_off(&at),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+            shared: self.shared.clone(),
+            shared_base: self.shared_base,
+            may_write: self.may_write,
+        }
+    }
     /// data: start-aligned but maybe with shorter length
     /// assume that all frames were cleared before
     pub fn copy_data(&mut self, page_table: &PageTable, data: &[u8]) {
@@ -143,21 +767,54 @@ impl MapArea {
 pub struct MemorySet {
     page_table: PageTable, // 管理页表本身的页帧
     areas: Vec<MapArea>,   // 管理映射区域的页帧
+    /// the user stack grows down from here
+    stack_top: usize,
+    /// RLIMIT_STACK of the task, how far the stack may grow below stack_top
+    stack_limit: usize,
+    /// the hard RLIMIT_STACK, which stack_limit may not be raised above
+    stack_limit_max: usize,
+    /// the lowest address mmap picks when no hint is given
+    mmap_base: usize,
+    /// the heap pages, from the end of the image up to the break,
+    /// a lazy area of their own while not empty
+    heap_start: VirtPageNum,
+    heap_end: VirtPageNum,
 }
 
 impl MemorySet {
-    pub fn new_bare() -> Self {
+    pub fn new_bare() -> Result<Self, OutOfMemory> {
+        Ok(Self::with_page_table(PageTable::new()?))
+    }
+    fn with_page_table(page_table: PageTable) -> Self {
         Self {
-            page_table: PageTable::new(),
+            page_table,
             areas: Vec::new(),
+            stack_top: user_stack_top(),
+            stack_limit: USER_STACK_LIMIT,
+            stack_limit_max: USER_STACK_LIMIT_MAX,
+            mmap_base: MMAP_BASE,
+            heap_start: VirtPageNum(0),
+            heap_end: VirtPageNum(0),
         }
     }
-    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    /// the soft and the hard RLIMIT_STACK
+    pub fn stack_limit(&self) -> (usize, usize) {
+        (self.stack_limit, self.stack_limit_max)
+    }
+    /// The stack grows up to the new soft limit from now on, as far as
+    /// the areas under it leave room. The caller checks that `limit <= max`.
+    pub fn set_stack_limit(&mut self, limit: usize, max: usize) {
+        self.stack_limit = limit;
+        self.stack_limit_max = max;
+    }
+    /// map and add the area, it is dropped if out of memory
+    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) -> Result<(), OutOfMemory> {
+        map_area.map(&mut self.page_table)?;
         if let Some(data) = data {
             map_area.copy_data(&self.page_table, data);
         }
         self.areas.push(map_area);
+        Ok(())
     }
     /// insert a new area to the memory set
     pub fn insert_framed_area(
@@ -165,11 +822,73 @@ impl MemorySet {
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
+    ) -> Result<(), OutOfMemory> {
         self.push(
             MapArea::new(start_va, end_va, MapType::Framed, permission),
             None,
-        );
+        )
+    }
+
+    /// insert a new area whose frames are allocated on first touch
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) -> Result<(), OutOfMemory> {
+        self.push(
+            MapArea::new(start_va, end_va, MapType::Lazy, permission),
+            None,
+        )
+    }
+
+    /// Attach `pages` of `frames` from frame `offset` at `start_va`,
+    /// which can not be made writable unless `may_write`.
+    pub fn insert_shared_area(
+        &mut self,
+        start_va: VirtAddr,
+        pages: usize,
+        frames: Arc<SharedFrames>,
+        offset: usize,
+        may_write: bool,
+        permission: MapPermission,
+    ) -> Result<(), OutOfMemory> {
+        let mut area =
+            MapArea::new_shared(start_va, pages, frames, offset, MapType::Shared, permission);
+        area.may_write = may_write;
+        self.push(area, None)
+    }
+
+    /// map `pages` of `frames` from frame `offset` at `start_va`, copied on write
+    pub fn insert_private_area(
+        &mut self,
+        start_va: VirtAddr,
+        pages: usize,
+        frames: Arc<SharedFrames>,
+        offset: usize,
+        permission: MapPermission,
+    ) -> Result<(), OutOfMemory> {
+        self.push(
+            MapArea::new_shared(start_va, pages, frames, offset, MapType::CopyOnWrite, permission),
+            None,
+        )
+    }
+
+    /// Detach the System V segment attached at `start`,
+    /// return false if there is none.
+    pub fn remove_shared_area(&mut self, start: VirtPageNum) -> bool {
+        match self.areas.iter().position(|area| {
+            area.map_type == MapType::Shared
+                && !area.shared_frames().is_file()
+                && area.vpn_range.get_start() == start
+        }) {
+            Some(idx) => {
+                let mut area = self.areas.remove(idx);
+                area.unmap(&mut self.page_table);
+                true
+            }
+            None => false,
+        }
     }
 
     //
@@ -187,6 +906,294 @@ impl MemorySet {
         }
     }
 
+    /// Whether any area has pages in `[start, end)`
+    pub fn overlaps(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        self.areas
+            .iter()
+            .any(|area| area.vpn_range.get_start() < end && start < area.vpn_range.get_end())
+    }
+
+    /// Find `pages` free pages of user space, the lowest ones at or above `hint`.
+    pub fn find_free_range(&self, hint: VirtPageNum, pages: usize) -> Option<VirtPageNum> {
+        let mut ranges: Vec<(VirtPageNum, VirtPageNum)> = self
+            .areas
+            .iter()
+            .map(|area| (area.vpn_range.get_start(), area.vpn_range.get_end()))
+            .collect();
+        // nor can it use the addresses of the kernel global pages
+        ranges.push((
+            VirtAddr::from(stext as usize).floor(),
+            VirtAddr::from(MEMORY_END).ceil(),
+        ));
+        ranges.sort();
+        let mut start = hint.0;
+        for (area_start, area_end) in ranges {
+            if area_end.0 <= start {
+                continue;
+            }
+            if area_start.0 >= start.checked_add(pages)? {
+                break;
+            }
+            start = area_end.0;
+        }
+        let end = start.checked_add(pages)?.checked_mul(PAGE_SIZE)?;
+        // keep clear of where the user stack grows
+        (is_user_range(VirtAddr::from(VirtPageNum(start)).0, end)
+            && end <= self.stack_top - self.stack_limit)
+            .then_some(VirtPageNum(start))
+    }
+
+    /// The frames and the index of the file page `vpn` is mapped from
+    /// if it is still to be read in, for the caller to read it with `read_in`
+    /// before `handle_page_fault`.
+    pub fn file_page(&self, vpn: VirtPageNum) -> Option<(Arc<SharedFrames>, usize)> {
+        let area = self.areas.iter().find(|area| area.contains(vpn))?;
+        let frames = area.shared.as_ref()?;
+        let index = area.shared_index(vpn);
+        frames.unread(index).then(|| (frames.clone(), index))
+    }
+
+    /// Handle a page fault at `vpn` needing `access`, an area allowing it
+    /// gets the page mapped, swapped in, read from its file or copied on write.
+    /// `sp` is the user stack pointer, the stack only grows for faults near it.
+    /// Invalid if the fault is a real one.
+    pub fn handle_page_fault(
+        &mut self,
+        vpn: VirtPageNum,
+        access: MapPermission,
+        sp: usize,
+    ) -> Result<(), FaultError> {
+        if !self.areas.iter().any(|area| area.contains(vpn)) && !self.grow_stack(vpn, sp) {
+            return Err(FaultError::Invalid);
+        }
+        let area = self.areas.iter_mut().find(|area| area.contains(vpn)).unwrap();
+        if !area.map_perm.contains(access) {
+            return Err(FaultError::Invalid);
+        }
+        let pte = self.page_table.translate(vpn);
+        // the page is mapped, the TLB had an entry from before it was
+        let needed = PTEFlags::from_bits(access.bits()).unwrap() | PTEFlags::V | PTEFlags::U;
+        if pte.map_or(false, |pte| pte.flags().contains(needed)) {
+            self.page_table.flush(vpn);
+            return Ok(());
+        }
+        match area.map_type {
+            MapType::CopyOnWrite => area.copy_on_write(&mut self.page_table, vpn, access)?,
+            _ if pte.map_or(false, |pte| pte.is_valid()) => return Err(FaultError::Invalid),
+            MapType::Shared => area.map_shared(&mut self.page_table, vpn)?,
+            MapType::Lazy => match pte.and_then(|pte| pte.swap_slot()) {
+                Some(slot) => area.swap_in(&mut self.page_table, vpn, slot)?,
+                None => {
+                    let frame = frame_alloc().ok_or(OutOfMemory)?;
+                    area.map_swappable(&mut self.page_table, vpn, frame, None, false)?
+                }
+            },
+            _ => return Err(FaultError::Invalid),
+        }
+        // the memory set may be the active one
+        self.page_table.flush(vpn);
+        Ok(())
+    }
+
+    /// Grow the user stack down to `vpn` if it is within the limit and
+    /// not far below `sp`, return false if it is not or other areas are in the way.
+    fn grow_stack(&mut self, vpn: VirtPageNum, sp: usize) -> bool {
+        let top = VirtAddr::from(self.stack_top).floor();
+        let lowest = VirtAddr::from(self.stack_top - self.stack_limit).floor();
+        let near_sp = VirtAddr::from(sp.saturating_sub(STACK_SP_SLACK)).floor();
+        if vpn < lowest || vpn < near_sp || vpn >= top {
+            return false;
+        }
+        let idx = match self
+            .areas
+            .iter()
+            .position(|area| area.vpn_range.get_end() == top)
+        {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let start = self.areas[idx].vpn_range.get_start();
+        if vpn >= start || self.areas[idx].map_type != MapType::Lazy || self.overlaps(vpn, start) {
+            return false;
+        }
+        self.areas[idx].extend_down_to(vpn);
+        true
+    }
+
+    /// Write `data` at `va`, faulting in the pages on the way.
+    /// Used before the memory set is active, Invalid if a page can not be
+    /// faulted in, as for a stack past its limit.
+    pub fn write_user(&mut self, mut va: usize, mut data: &[u8]) -> Result<(), FaultError> {
+        while !data.is_empty() {
+            let vpn = VirtAddr::from(va).floor();
+            if !self.translate(vpn).map_or(false, |pte| pte.is_valid()) {
+                // the stack is being built down to va
+                self.handle_page_fault(vpn, MapPermission::W, va)?;
+            }
+            let pte = self.page_table.find_pte(vpn).unwrap();
+            pte.mark_dirty();
+            let offset = VirtAddr::from(va).page_offset();
+            let len = data.len().min(PAGE_SIZE - offset);
+            pte.ppn().get_bytes_array()[offset..offset + len].copy_from_slice(&data[..len]);
+            va += len;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    /// the pages of the heap, `[start, end)`
+    pub fn heap(&self) -> (VirtPageNum, VirtPageNum) {
+        (self.heap_start, self.heap_end)
+    }
+
+    /// Move the end of the heap to `new_end`, its area is created when it grows
+    /// from empty and removed when emptied.
+    /// Return false if it would overlap another area, leave user space
+    /// or need frames when out of memory.
+    pub fn set_heap_end(&mut self, new_end: VirtPageNum) -> bool {
+        let (start, end) = (self.heap_start, self.heap_end);
+        if new_end < start || !is_user_range(VirtAddr::from(start).0, VirtAddr::from(new_end).0) {
+            return false;
+        }
+        if new_end > end && self.overlaps(end, new_end) {
+            return false;
+        }
+        if start == end {
+            if new_end > start
+                && self
+                    .insert_lazy_area(
+                        start.into(),
+                        new_end.into(),
+                        MapPermission::R | MapPermission::W | MapPermission::U,
+                    )
+                    .is_err()
+            {
+                return false;
+            }
+        } else {
+            // nothing else has pages in the heap and it is never split,
+            // so the area there is the whole heap
+            let idx = self.areas.iter().position(|area| area.contains(start)).unwrap();
+            if new_end > end {
+                if self.areas[idx]
+                    .append_to(&mut self.page_table, new_end)
+                    .is_err()
+                {
+                    return false;
+                }
+            } else if new_end == start {
+                self.areas.remove(idx).unmap(&mut self.page_table);
+            } else {
+                self.areas[idx].shrink_to(&mut self.page_table, new_end);
+            }
+        }
+        self.heap_end = new_end;
+        true
+    }
+
+    /// Whether `[start, end)` has some of the heap pages but not all of them,
+    /// which would split the heap area.
+    pub fn cuts_heap(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        start < self.heap_end
+            && self.heap_start < end
+            && (self.heap_start < start || end < self.heap_end)
+    }
+
+    /// Split the areas partly in `[start, end)`,
+    /// so that every area is either all in the range or all out of it.
+    fn split_range(&mut self, start: VirtPageNum, end: VirtPageNum) {
+        let mut split = Vec::new();
+        for mut area in self.areas.drain(..) {
+            let (area_start, area_end) = (area.vpn_range.get_start(), area.vpn_range.get_end());
+            if area_end <= start || end <= area_start {
+                split.push(area);
+                continue;
+            }
+            if area_start < start {
+                let rest = area.split_off(start);
+                split.push(area);
+                area = rest;
+            }
+            if end < area_end {
+                split.push(area.split_off(end));
+            }
+            split.push(area);
+        }
+        self.areas = split;
+    }
+
+    fn in_range(area: &MapArea, start: VirtPageNum, end: VirtPageNum) -> bool {
+        start <= area.vpn_range.get_start() && area.vpn_range.get_end() <= end
+    }
+
+    /// Unmap the pages in `[start, end)`,
+    /// areas partly in the range are split and keep their other pages.
+    pub fn remove_range(&mut self, start: VirtPageNum, end: VirtPageNum) {
+        // the heap is left empty if all of it goes
+        if start <= self.heap_start && self.heap_end <= end {
+            self.heap_end = self.heap_start;
+        }
+        self.split_range(start, end);
+        let page_table = &mut self.page_table;
+        self.areas.retain_mut(|area| {
+            if !Self::in_range(area, start, end) {
+                return true;
+            }
+            area.unmap(page_table);
+            false
+        });
+    }
+
+    /// Whether all the pages in `[start, end)` are in some area
+    pub fn is_mapped(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        let mapped: usize = self
+            .areas
+            .iter()
+            .map(|area| {
+                let from = area.vpn_range.get_start().max(start);
+                let to = area.vpn_range.get_end().min(end);
+                to.0.saturating_sub(from.0)
+            })
+            .sum();
+        mapped == end.0 - start.0
+    }
+
+    /// Whether the areas in `[start, end)` may be made writable,
+    /// shared mappings of files opened read-only may not.
+    pub fn may_write(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        self.areas
+            .iter()
+            .filter(|area| area.vpn_range.get_start() < end && start < area.vpn_range.get_end())
+            .all(|area| area.may_write)
+    }
+
+    /// Change the permission of the pages in `[start, end)` to `perm`,
+    /// areas partly in the range are split and keep their permission elsewhere.
+    pub fn protect_range(&mut self, start: VirtPageNum, end: VirtPageNum, perm: MapPermission) {
+        self.split_range(start, end);
+        for area in self.areas.iter_mut() {
+            if Self::in_range(area, start, end) {
+                area.set_perm(&mut self.page_table, perm);
+            }
+        }
+    }
+
+    /// Write the dirty pages of shared file mappings in `[start, end)` back
+    /// to their files, return false if some pages in the range are not mapped.
+    pub fn sync_range(&mut self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        for area in self.areas.iter() {
+            let from = area.vpn_range.get_start().max(start);
+            let to = area.vpn_range.get_end().min(end);
+            if from >= to || area.map_type != MapType::Shared || !area.shared_frames().is_file() {
+                continue;
+            }
+            for vpn in VPNRange::new(from, to) {
+                area.write_back(&mut self.page_table, vpn);
+            }
+        }
+        self.is_mapped(start, end)
+    }
+
     pub fn activate(&self) {
         let satp = self.page_table.token();
         unsafe {
@@ -199,6 +1206,17 @@ impl MemorySet {
         self.page_table.token()
     }
 
+    pub fn mmap_base(&self) -> usize {
+        self.mmap_base
+    }
+
+    /// The frames only this memory set holds: its page tables, its own frames
+    /// and its lazy pages in memory, frames of shared areas are not counted.
+    pub fn resident_frames(&self) -> usize {
+        let own: usize = self.areas.iter().map(|area| area.data_frames.len()).sum();
+        self.page_table.frames() + own + swap::resident_pages(self.token())
+    }
+
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
@@ -206,7 +1224,15 @@ impl MemorySet {
     /// clear all user space pages
     /// it will not clear the kernel space
     pub fn recycle_data_pages(&mut self) {
+        // the frames of lazy areas are not dropped with the areas,
+        // and the dirty pages of shared files are written back
+        for area in self.areas.iter_mut() {
+            if matches!(area.map_type, MapType::Lazy | MapType::Shared) {
+                area.unmap(&mut self.page_table);
+            }
+        }
         self.areas.clear();
+        swap::forget(self.token());
     }
 
     /// map trampoline
@@ -216,18 +1242,23 @@ impl MemorySet {
     /// It's physical address is different from virtual address.
     /// It do not append MapArea to self.areas,
     /// so the trampoline will not be recycled when recycle_data_pages.
-    fn map_trampoline(&mut self) {
+    fn map_trampoline(&mut self) -> Result<(), OutOfMemory> {
         self.page_table.map(
             VirtAddr::from(TRAMPOLINE).into(),
             PhysAddr::from(strampoline as usize).into(),
             PTEFlags::R | PTEFlags::X,
-        );
+        )
     }
 
     pub fn new_kernel() -> Self {
-        let mut memory_set = Self::new_bare();
+        // the kernel can not run without its memory set
+        let mut memory_set = Self::with_page_table(
+            PageTable::new_kernel().expect("no frames for the kernel memory set"),
+        );
         // map trampoline
-        memory_set.map_trampoline();
+        memory_set
+            .map_trampoline()
+            .expect("no frames for the kernel memory set");
         // map kernel sections
         println!(".text [{:#x}, {:#x})", stext as usize, etext as usize);
         println!(".rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
@@ -249,7 +1280,8 @@ impl MemorySet {
                 MapPermission::R | MapPermission::X,
             ),
             None,
-        );
+        )
+        .expect("no frames for the kernel memory set");
         println!("mapping .rodata section");
         memory_set.push(
             MapArea::new(
@@ -259,7 +1291,8 @@ impl MemorySet {
                 MapPermission::R,
             ),
             None,
-        );
+        )
+        .expect("no frames for the kernel memory set");
         println!("mapping .data section");
         memory_set.push(
             MapArea::new(
@@ -269,7 +1302,8 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )
+        .expect("no frames for the kernel memory set");
         println!("mapping .bss section");
         memory_set.push(
             MapArea::new(
@@ -279,7 +1313,8 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )
+        .expect("no frames for the kernel memory set");
         println!("mapping physical memory");
         memory_set.push(
             MapArea::new(
@@ -289,7 +1324,8 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
+        )
+        .expect("no frames for the kernel memory set");
         println!("kernel memory set initialized");
         for pair in MMIO {
             memory_set.push(
@@ -300,60 +1336,114 @@ impl MemorySet {
                     MapPermission::R | MapPermission::W,
                 ),
                 None,
-            );
+            )
+            .expect("no frames for the kernel memory set");
         }
         memory_set
     }
 
     /// Include sections in elf and trampoline and TrapContext and user stack,
-    /// also returns user_sp and entry point.
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut memory_set = Self::new_bare();
-        // map trampoline
-        memory_set.map_trampoline();
-        // map program headers of elf, with U flag
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
-        let elf_header = elf.header;
-        let magic = elf_header.pt1.magic;
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
-        let ph_count = elf_header.pt2.ph_count();
-        let mut max_end_vpn = VirtPageNum(0);
-        for i in 0..ph_count {
-            let ph = elf.program_header(i).unwrap();
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
-                let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
-                let mut map_perm = MapPermission::U;
-                let ph_flags = ph.flags();
-                if ph_flags.is_read() {
-                    map_perm |= MapPermission::R;
+    /// also returns user_sp, entry point, the end of the image and the auxiliary vector.
+    /// An ET_DYN image is moved up by a load bias and its relative relocations applied,
+    /// unless it has an interpreter, which is loaded from `interp_data` to relocate it
+    /// and gets control first.
+    /// The whole image is validated before any frame is allocated,
+    /// so a malformed file never reaches `push`.
+    /// With `randomize` the stack top, the mmap base and the load bias are moved by random pages.
+    pub fn from_elf(
+        elf_data: &[u8],
+        interp_data: Option<&[u8]>,
+        randomize: bool,
+        stack_limit: usize,
+    ) -> Result<(Self, usize, usize, usize, Vec<(usize, usize)>), ElfLoadError> {
+        let image = ElfImage::parse(elf_data)?;
+        let interp = match (image.interp, interp_data) {
+            (None, _) => None,
+            (Some(_), Some(interp_data)) => {
+                let interp = ElfImage::parse(interp_data)?;
+                // the interpreter has to do everything by itself
+                if interp.interp.is_some() {
+                    return Err(ElfLoadError::BadInterpreter);
                 }
-                if ph_flags.is_write() {
-                    map_perm |= MapPermission::W;
+                if interp.symbolic {
+                    return Err(ElfLoadError::BadRelocation);
                 }
-                if ph_flags.is_execute() {
-                    map_perm |= MapPermission::X;
+                Some(interp)
+            }
+            (Some(_), None) => return Err(ElfLoadError::BadInterpreter),
+        };
+        // without an interpreter nobody else resolves symbols
+        if interp.is_none() && image.symbolic {
+            return Err(ElfLoadError::BadRelocation);
+        }
+        let mut bias = 0;
+        if image.relocatable {
+            bias = ELF_DYN_BASE;
+            if randomize {
+                bias += random_below(ELF_DYN_RANDOM_PAGES) * PAGE_SIZE;
+            }
+        }
+        let max_end = image
+            .end()
+            .checked_add(bias)
+            .filter(|&end| is_user_range(0, end))
+            .ok_or(ElfLoadError::BadSegment)?;
+        let max_end_va: VirtAddr = VirtAddr::from(max_end).ceil().into();
+        // the image must leave room for the stack to grow
+        if max_end_va.0 > user_stack_top() - stack_limit {
+            return Err(ElfLoadError::BadSegment);
+        }
+        let mut memory_set = Self::new_bare()?;
+        memory_set.stack_limit = stack_limit;
+        memory_set.heap_start = max_end_va.into();
+        memory_set.heap_end = max_end_va.into();
+        if randomize {
+            // the stack still has room to grow above the image
+            let room = (user_stack_top() - stack_limit - max_end_va.0) / PAGE_SIZE;
+            memory_set.stack_top -= random_below(room.min(STACK_RANDOM_PAGES) + 1) * PAGE_SIZE;
+            memory_set.mmap_base += random_below(MMAP_RANDOM_PAGES) * PAGE_SIZE;
+        }
+        let user_stack_top = memory_set.stack_top;
+        let user_stack_bottom = user_stack_top - USER_STACK_SIZE;
+        // map trampoline
+        memory_set.map_trampoline()?;
+        // map program headers of elf, with U flag
+        memory_set.map_elf(&image, bias)?;
+        let mut entry_point = image.elf.header.pt2.entry_point() as usize + bias;
+        let mut auxv = Vec::new();
+        if let Some(phdr) = image.phdr_va() {
+            auxv.push((AT_PHDR, phdr + bias));
+            auxv.push((AT_PHENT, image.elf.header.pt2.ph_entry_size() as usize));
+            auxv.push((AT_PHNUM, image.elf.header.pt2.ph_count() as usize));
+        }
+        auxv.push((AT_PAGESZ, PAGE_SIZE));
+        auxv.push((AT_ENTRY, entry_point));
+        match interp {
+            None => memory_set.relocate(&image, bias)?,
+            Some(interp) => {
+                // an ET_DYN interpreter goes where mmap would put it
+                let mut interp_bias = 0;
+                let start = VirtAddr::from(interp.segments[0].0).floor();
+                let end = VirtAddr::from(interp.end()).ceil();
+                if interp.relocatable {
+                    let hint = VirtAddr::from(memory_set.mmap_base).floor();
+                    let free = memory_set
+                        .find_free_range(hint, end.0 - start.0)
+                        .ok_or(ElfLoadError::BadInterpreter)?;
+                    interp_bias = (free.0 - start.0) * PAGE_SIZE;
+                } else if memory_set.overlaps(start, end) {
+                    return Err(ElfLoadError::SegmentOverlap);
+                } else if VirtAddr::from(end).0 > memory_set.stack_top - stack_limit {
+                    return Err(ElfLoadError::BadSegment);
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
-                println!(
-                    "mapping [{:#x}, {:#x}) with permission {:?}",
-                    start_va.floor().0,
-                    end_va.ceil().0,
-                    map_perm
-                );
-                max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                memory_set.map_elf(&interp, interp_bias)?;
+                memory_set.relocate(&interp, interp_bias)?;
+                entry_point = interp.elf.header.pt2.entry_point() as usize + interp_bias;
+                auxv.push((AT_BASE, interp_bias));
             }
         }
+        auxv.push((AT_NULL, 0));
         // map user stack with U flags
-        let max_end_va: VirtAddr = max_end_vpn.into();
-        let mut user_stack_bottom: usize = max_end_va.into();
-        // guard page
-        user_stack_bottom += PAGE_SIZE;
-        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
         println!(
             "mapping user stack [{:#x}, {:#x}) with permission {:?}",
             user_stack_bottom,
@@ -364,11 +1454,11 @@ impl MemorySet {
             MapArea::new(
                 user_stack_bottom.into(),
                 user_stack_top.into(),
-                MapType::Framed,
+                MapType::Lazy,
                 MapPermission::R | MapPermission::W | MapPermission::U,
             ),
             None,
-        );
+        )?;
         // map TrapContext
         println!(
             "mapping TrapContext [{:#x}, {:#x}) with permission {:?}",
@@ -384,42 +1474,174 @@ impl MemorySet {
                 MapPermission::R | MapPermission::W,
             ),
             None,
-        );
-        (
-            memory_set,
-            user_stack_top,
-            elf.header.pt2.entry_point() as usize,
-        )
+        )?;
+        Ok((memory_set, user_stack_top, entry_point, max_end_va.0, auxv))
+    }
+    /// Map the loadable segments of `image` moved up by `bias`.
+    fn map_elf(&mut self, image: &ElfImage, bias: usize) -> Result<(), ElfLoadError> {
+        for &(start, end, map_perm, offset, file_end) in image.segments.iter() {
+            let (start_va, end_va): (VirtAddr, VirtAddr) =
+                match (start.checked_add(bias), end.checked_add(bias)) {
+                    (Some(start), Some(end)) if is_user_range(start, end) => {
+                        (start.into(), end.into())
+                    }
+                    _ => return Err(ElfLoadError::BadSegment),
+                };
+            let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+            println!(
+                "mapping [{:#x}, {:#x}) with permission {:?}",
+                start_va.floor().0,
+                end_va.ceil().0,
+                map_perm
+            );
+            self.push(map_area, Some(&image.elf.input[offset..file_end]))?;
+        }
+        Ok(())
+    }
+    /// Apply the relative relocations of `image` loaded at `bias`.
+    fn relocate(&mut self, image: &ElfImage, bias: usize) -> Result<(), ElfLoadError> {
+        if !image.relocatable {
+            return Ok(());
+        }
+        for &(offset, addend) in image.relative.iter() {
+            let value = bias.wrapping_add(addend as usize);
+            self.write_user(offset + bias, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+    /// Take the layout of a user space and where its pages are, for
+    /// `from_snapshot` to copy it for a child without it borrowed.
+    pub fn snapshot(&self) -> MemorySnapshot {
+        let areas = self
+            .areas
+            .iter()
+            .map(|area| {
+                let pages = match area.map_type {
+                    // the child maps the same frames
+                    MapType::Shared => Vec::new(),
+                    // and shares the frames of the file until it writes them too
+                    MapType::CopyOnWrite => area
+                        .data_frames
+                        .iter()
+                        .map(|(&vpn, frame)| (vpn, PageSource::Frame(frame.ppn)))
+                        .collect(),
+                    // untouched pages stay untouched
+                    MapType::Lazy => area
+                        .vpn_range
+                        .into_iter()
+                        .filter_map(|vpn| {
+                            let pte = self.translate(vpn)?;
+                            if pte.is_valid() {
+                                Some((vpn, PageSource::Frame(pte.ppn())))
+                            } else {
+                                pte.swap_slot().map(|slot| (vpn, PageSource::Slot(slot)))
+                            }
+                        })
+                        .collect(),
+                    _ => area
+                        .vpn_range
+                        .into_iter()
+                        .map(|vpn| (vpn, PageSource::Frame(self.translate(vpn).unwrap().ppn())))
+                        .collect(),
+                };
+                (MapArea::from_another(area), pages)
+            })
+            .collect();
+        MemorySnapshot {
+            stack_top: self.stack_top,
+            stack_limit: self.stack_limit,
+            stack_limit_max: self.stack_limit_max,
+            mmap_base: self.mmap_base,
+            heap_start: self.heap_start,
+            heap_end: self.heap_end,
+            areas,
+        }
     }
-    pub fn from_existed_user(user_space: &MemorySet) -> Self {
-        let mut memory_set = Self::new_bare();
+    /// Copy the user space of a parent for its child from its snapshot,
+    /// a page at a time with preemption points between the pages.
+    /// The frames already copied are freed if out of memory.
+    pub fn from_snapshot(snapshot: MemorySnapshot) -> Result<Self, OutOfMemory> {
+        let mut memory_set = Self::new_bare()?;
+        memory_set.stack_top = snapshot.stack_top;
+        memory_set.stack_limit = snapshot.stack_limit;
+        memory_set.stack_limit_max = snapshot.stack_limit_max;
+        memory_set.mmap_base = snapshot.mmap_base;
+        memory_set.heap_start = snapshot.heap_start;
+        memory_set.heap_end = snapshot.heap_end;
         // map trampoline
-        memory_set.map_trampoline();
-        for area in user_space.areas.iter() {
-            let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
-            for vpn in area.vpn_range {
-                let src_ppn = user_space.translate(vpn).unwrap().ppn();
-                let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
-                dst_ppn
-                    .get_bytes_array()
-                    .copy_from_slice(src_ppn.get_bytes_array());
+        memory_set.map_trampoline()?;
+        for (area, pages) in snapshot.areas {
+            memory_set.push(area, None)?;
+            for (vpn, src) in pages {
+                {
+                    let _preempt = PreemptGuard::new();
+                    memory_set.copy_page(vpn, src)?;
+                }
+                preempt_point();
             }
         }
-        memory_set
+        Ok(memory_set)
+    }
+    /// copy page `vpn` of the last area pushed from `src`
+    fn copy_page(&mut self, vpn: VirtPageNum, src: PageSource) -> Result<(), OutOfMemory> {
+        let area = self.areas.last_mut().unwrap();
+        let fill = |ppn: PhysPageNum| match src {
+            PageSource::Frame(src_ppn) => ppn
+                .get_bytes_array()
+                .copy_from_slice(src_ppn.get_bytes_array()),
+            PageSource::Slot(slot) => swap::read_slot(slot, ppn),
+        };
+        match area.map_type {
+            MapType::Lazy => {
+                let frame = frame_alloc().ok_or(OutOfMemory)?;
+                fill(frame.ppn);
+                area.map_swappable(&mut self.page_table, vpn, frame, None, true)
+            }
+            MapType::CopyOnWrite => {
+                let frame = frame_alloc().ok_or(OutOfMemory)?;
+                fill(frame.ppn);
+                self.page_table.map(vpn, frame.ppn, area.pte_flags())?;
+                area.data_frames.insert(vpn, frame);
+                Ok(())
+            }
+            // mapped with the area
+            _ => {
+                fill(self.page_table.translate(vpn).unwrap().ppn());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for MemorySet {
+    fn drop(&mut self) {
+        self.recycle_data_pages();
     }
 }
 
+/// The user stack grows down from the top of the lower half of the address space,
+/// 256 GiB in Sv39 and 128 TiB in Sv48
+pub fn user_stack_top() -> usize {
+    1 << (va_width() - 1)
+}
+
+/// Check that `[start, end)` is a canonical user address range
+/// which stays clear of the TrapContext and trampoline pages,
+/// and of `[stext, MEMORY_END)`, which the kernel maps global in every address space.
+pub fn is_user_range(start: usize, end: usize) -> bool {
+    start <= end
+        && end <= TRAP_CONTEXT
+        && usize::from(VirtAddr::from(start)) == start
+        && (end == start || usize::from(VirtAddr::from(end - 1)) == end - 1)
+        && (end <= stext as usize || MEMORY_END <= start || end == start)
+}
+
 lazy_static! {
     /// a memory set instance through lazy_static! managing kernel space
     pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> =
         Arc::new(unsafe { UPSafeCell::new(MemorySet::new_kernel()) });
 }
 
-pub fn kernel_token() -> usize {
-    KERNEL_SPACE.exclusive_access().token()
-}
-
 #[allow(unused)]
 pub fn remap_test() {
     let mut kernel_space = KERNEL_SPACE.exclusive_access();
@@ -450,5 +1672,13 @@ pub fn remap_test() {
             .executable(),
         false,
     );
+    // physical memory is mapped by huge pages where they are aligned
+    let last_page = VirtAddr::from(MEMORY_END - 1).floor();
+    let (_, level) = kernel_space.page_table.find_leaf(last_page).unwrap();
+    assert!(level < levels() - 1);
+    assert_eq!(
+        kernel_space.page_table.translate(last_page).unwrap().ppn().0,
+        last_page.0
+    );
     println!("remap_test passed!");
 }
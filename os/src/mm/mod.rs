@@ -1,27 +1,37 @@
 mod address;
+mod asid;
 mod frame_allocator;
 mod heap_allocator;
 mod memory_set;
 mod page_table;
+mod shared;
+mod shm;
+mod swap;
 
-pub use address::{PhysAddr, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
-pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker};
-pub use memory_set::MemorySet;
+pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
+pub use frame_allocator::{frame_alloc_contiguous, frame_stats, FrameTracker, OutOfMemory};
+pub use heap_allocator::heap_stats;
+pub use memory_set::{elf_interpreter, is_user_range, ElfLoadError, FaultError, MemorySet};
 pub use memory_set::KERNEL_SPACE;
-pub use memory_set::{kernel_token, MapPermission};
+pub use memory_set::MapPermission;
 pub use page_table::{
-    translated_byte_buffer, translated_ref, translated_refmut, translated_str, PageTable,
+    copy_from_user, copy_to_user, translated_byte_buffer, translated_str, PageTable, StrError,
     UserBuffer,
 };
+pub use shared::{flush_write_backs, SharedFrames};
+pub use shm::{shm_create, shm_find, shm_frames, shm_remove, IPC_PRIVATE};
+pub use swap::{pin_user_pages, unpin_user_pages};
 
 use crate::println;
 
 pub fn init() {
     heap_allocator::init_heap();
     frame_allocator::init_frame_allocator();
+    page_table::init_paging_mode();
     // set the satp to kernel space
     // because the kernel space is mapped to the same physical address
     // pc can work well to jump to the kernel space
     KERNEL_SPACE.exclusive_access().activate();
+    swap::init();
     println!("++++ setup memory!     ++++");
 }
@@ -1,12 +1,20 @@
 use alloc::vec::Vec;
 use alloc::{string::String, vec};
+use core::arch::asm;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::satp;
 
 use super::PhysAddr;
 use super::{
-    address::{PhysPageNum, StepByOne, VirtPageNum},
-    frame_allocator::{frame_alloc, FrameTracker},
-    VirtAddr,
+    address::{va_width, PhysPageNum, StepByOne, VirtPageNum},
+    asid::{self, asid_alloc, AsidHandle},
+    frame_allocator::{frame_alloc, FrameTracker, OutOfMemory},
+    FaultError, MapPermission, VirtAddr,
 };
+use crate::config::{PAGE_SIZE, PAGING_SV48, USER_STR_MAX};
+use crate::println;
+use crate::task::{fault_in_current, out_of_memory};
 
 bitflags! {
     pub struct PTEFlags: u8 {
@@ -21,6 +29,84 @@ bitflags! {
     }
 }
 
+/// levels of page tables, 3 for Sv39 and 4 for Sv48, the root is level 0.
+/// Chosen by `init_paging_mode` before any page table is built.
+static LEVELS: AtomicUsize = AtomicUsize::new(3);
+
+pub(super) const MAX_LEVELS: usize = 4;
+
+pub(super) fn levels() -> usize {
+    LEVELS.load(Ordering::Relaxed)
+}
+
+/// MODE field of satp, 8 for Sv39 and 9 for Sv48
+fn satp_mode() -> usize {
+    levels() + 5
+}
+
+/// ASID field of satp, 16 bits at most
+const ASID_MASK: usize = 0xffff;
+
+/// pages under a leaf entry at `level`: a page at the last level,
+/// a 2 MiB megapage above it, then a 1 GiB gigapage and a 512 GiB terapage
+pub(super) fn level_pages(level: usize) -> usize {
+    1 << (9 * (levels() - 1 - level))
+}
+
+/// Use Sv48 if PAGING_SV48 asks for it and the hart supports it, Sv39 otherwise,
+/// and hand out the ASIDs the hart has.
+pub fn init_paging_mode() {
+    let asid_max = match PAGING_SV48.then(|| probe_satp(4)).flatten() {
+        Some(asid_max) => {
+            LEVELS.store(4, Ordering::Relaxed);
+            asid_max
+        }
+        None => probe_satp(3).expect("the hart does not support Sv39"),
+    };
+    asid::init(asid_max);
+    println!(
+        "[kernel] paging mode Sv{}, {} ASID bits",
+        va_width(),
+        (asid_max + 1).trailing_zeros()
+    );
+}
+
+/// A write of an unsupported mode to satp has no effect and the ASID bits
+/// it does not have read as zero, so turn on paging with `levels` levels and
+/// all ones in ASID, with a table mapping only the gigapage the kernel runs in.
+/// Return the largest ASID if the mode is supported.
+fn probe_satp(levels: usize) -> Option<usize> {
+    extern "C" {
+        fn stext();
+    }
+    let root = frame_alloc().expect("no frames to probe the paging mode");
+    let table = frame_alloc().expect("no frames to probe the paging mode");
+    let kernel = stext as usize;
+    let leaf = PageTableEntry::new(
+        PhysPageNum::from(kernel >> 30 << 18),
+        PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::X | PTEFlags::A | PTEFlags::D,
+    );
+    if levels == 4 {
+        root.ppn.get_pte_array()[kernel >> 39 & 511] = PageTableEntry::new(table.ppn, PTEFlags::V);
+        table.ppn.get_pte_array()[kernel >> 30 & 511] = leaf;
+    } else {
+        root.ppn.get_pte_array()[kernel >> 30 & 511] = leaf;
+    }
+    let mode = levels + 5;
+    unsafe {
+        satp::write(mode << 60 | ASID_MASK << 44 | root.ppn.0);
+        asm!("sfence.vma");
+        let bits = satp::read().bits();
+        satp::write(0);
+        asm!("sfence.vma");
+        (bits >> 60 == mode).then_some(bits >> 44 & ASID_MASK)
+    }
+}
+
+/// software bit of an invalid entry whose page is swapped out,
+/// the swap slot is kept in the ppn field
+const PTE_SWAPPED: usize = 1 << 8;
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct PageTableEntry {
@@ -36,6 +122,15 @@ impl PageTableEntry {
     pub fn empty() -> Self {
         PageTableEntry { bits: 0 }
     }
+    /// an invalid entry for a page swapped out to `slot`
+    pub fn swapped(slot: usize) -> Self {
+        PageTableEntry {
+            bits: slot << 10 | PTE_SWAPPED,
+        }
+    }
+    pub fn swap_slot(&self) -> Option<usize> {
+        (!self.is_valid() && self.bits & PTE_SWAPPED != 0).then_some(self.bits >> 10)
+    }
     pub fn ppn(&self) -> PhysPageNum {
         (self.bits >> 10 & ((1usize << 44) - 1)).into() // 44 bits
     }
@@ -54,10 +149,41 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         self.flags().intersects(PTEFlags::X)
     }
+    /// a valid entry pointing to a page rather than to the next level
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid()
+            && self
+                .flags()
+                .intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X)
+    }
+    pub fn accessed(&self) -> bool {
+        self.flags().intersects(PTEFlags::A)
+    }
+    pub fn dirty(&self) -> bool {
+        self.flags().intersects(PTEFlags::D)
+    }
+    pub fn clear_accessed(&mut self) {
+        self.bits &= !(PTEFlags::A.bits as usize);
+    }
+    /// as if the page was written, for writes not going through this entry
+    pub fn mark_dirty(&mut self) {
+        self.bits |= (PTEFlags::A | PTEFlags::D).bits as usize;
+    }
+    pub fn clear_dirty(&mut self) {
+        self.bits &= !(PTEFlags::D.bits as usize);
+    }
+    /// replace the R, W, X and U bits with those of `perm`
+    pub fn set_perm(&mut self, perm: PTEFlags) {
+        let mask = PTEFlags::R | PTEFlags::W | PTEFlags::X | PTEFlags::U;
+        self.bits = self.bits & !(mask.bits as usize) | (perm & mask).bits as usize;
+    }
 }
 
 pub struct PageTable {
     root_ppn: PhysPageNum,
+    /// tags the TLB entries of the table, 0 for the kernel and when there is none left
+    asid: usize,
+    asid_handle: Option<AsidHandle>,
 
     // frames for page table themselves
     // root_ppn 下的页表对应的页帧都归 PageTable 管理
@@ -65,21 +191,22 @@ pub struct PageTable {
 }
 
 impl PageTable {
-    /// Find a leaf page table entry by virtual page number.
-    /// Create the page tables if necessary.
-    fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    /// Find the page table entry of `vpn` at `level`.
+    /// Create the page tables if necessary, None if out of memory.
+    fn find_pte_create(&mut self, vpn: VirtPageNum, level: usize) -> Option<&mut PageTableEntry> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
         let mut result: Option<&mut PageTableEntry> = None;
-        for i in 0..3 {
+        for i in 0..levels() {
             // ppn 对应页帧是一个页表，返回页中 index 的页表项
             let pte = &mut ppn.get_pte_array()[idxs[i]];
-            if i == 2 {
+            if i == level {
                 result = Some(pte);
                 break;
             }
+            assert!(!pte.is_leaf(), "vpn {:?} is in a huge page", vpn);
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc()?;
                 // here the pte is valid,
                 // but it is not the last level pte
                 // so it will no return
@@ -95,44 +222,86 @@ impl PageTable {
         result
     }
 
-    /// Find a leaf page table entry by virtual page number.
+    /// Find the leaf page table entry of `vpn` and its level,
+    /// which is above the last level for a huge page.
     /// Return None if the entry does not exist.
-    fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    pub(super) fn find_leaf(&self, vpn: VirtPageNum) -> Option<(&mut PageTableEntry, usize)> {
         let idxs = vpn.indexes();
         let mut ppn = self.root_ppn;
-        let mut result: Option<&mut PageTableEntry> = None;
-        for i in 0..3 {
+        let levels = levels();
+        for i in 0..levels {
             let pte = &mut ppn.get_pte_array()[idxs[i]];
-            if i == 2 {
-                result = Some(pte);
-                break;
+            if i == levels - 1 || pte.is_leaf() {
+                return Some((pte, i));
             }
             if !pte.is_valid() {
                 return None;
             }
             ppn = pte.ppn();
         }
-        result
+        unreachable!()
     }
-    /// 将 vpn 映射到 ppn
-    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        // here is the last level pte
+
+    /// Find a leaf page table entry by virtual page number,
+    /// the entry of the whole huge page if `vpn` is in one.
+    /// Return None if the entry does not exist.
+    pub(super) fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+        self.find_leaf(vpn).map(|(pte, _)| pte)
+    }
+    /// 将 vpn 映射到 ppn, 缺少页表所需的页帧时失败
+    pub fn map(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+    ) -> Result<(), OutOfMemory> {
+        self.map_huge(vpn, ppn, flags, levels() - 1)
+    }
+    /// Map the `level_pages(level)` pages from `vpn` to those from `ppn`
+    /// with one leaf entry at `level`, both must be aligned to the size.
+    pub fn map_huge(
+        &mut self,
+        vpn: VirtPageNum,
+        ppn: PhysPageNum,
+        flags: PTEFlags,
+        level: usize,
+    ) -> Result<(), OutOfMemory> {
+        let pages = level_pages(level);
+        assert!(
+            vpn.0 % pages == 0 && ppn.0 % pages == 0,
+            "huge page {:?} -> {:?} is not aligned",
+            vpn,
+            ppn
+        );
+        // here is the leaf pte
         // which should directly point to the physical page to be mapped
         // map a page should be not mapped before
         // so it should be invalid
         // note that the page of ppn do not belong to the page table
-        let pte = self.find_pte_create(vpn).unwrap();
+        let pte = self.find_pte_create(vpn, level).ok_or(OutOfMemory)?;
         // find_pte_create 中，自动创建了不存在的页表项
         // 页表本身对应的页表项已经设为 valid
-        // 但 find_pte_create 返回的是最后一级页表项，不一定是 valid
+        // 但 find_pte_create 返回的是 level 级的页表项，不一定是 valid
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        // the kernel space maps its stacks while it is active, user spaces with
+        // their own ASID take a spurious page fault instead if the invalid entry was cached
+        if self.asid == 0 {
+            self.flush(vpn);
+        }
+        Ok(())
     }
-    /// 回收 vpn 对应的页表项
+    /// 回收 vpn 对应的页表项, 对于大页回收整个大页
     pub fn unmap(&mut self, vpn: VirtPageNum) {
         let pte = self.find_pte(vpn).unwrap();
         assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
         *pte = PageTableEntry::empty();
+        self.flush(vpn);
+    }
+    /// Flush the TLB entries of `vpn` after its entry is changed,
+    /// the table may be the active one or have entries cached under its ASID.
+    pub fn flush(&self, vpn: VirtPageNum) {
+        asid::flush_page(self.asid, vpn);
     }
 
     /// no frames under the returned entity,
@@ -140,23 +309,40 @@ impl PageTable {
     pub fn from_token(satp: usize) -> Self {
         Self {
             root_ppn: PhysPageNum::from(satp & ((1usize << 44) - 1)),
+            asid: satp >> 44 & ASID_MASK,
+            asid_handle: None,
             frames: Vec::new(),
         }
     }
-    pub fn new() -> Self {
+    /// A user page table, with an ASID of its own if any is left.
+    pub fn new() -> Result<Self, OutOfMemory> {
+        let mut page_table = Self::new_kernel()?;
+        page_table.asid_handle = asid_alloc();
+        page_table.asid = page_table.asid_handle.as_ref().map_or(0, |handle| handle.0);
+        Ok(page_table)
+    }
+    /// The page table of the kernel space, with ASID 0.
+    pub fn new_kernel() -> Result<Self, OutOfMemory> {
         // alloc root frame
-        let frame = frame_alloc().unwrap();
-        PageTable {
+        let frame = frame_alloc().ok_or(OutOfMemory)?;
+        Ok(PageTable {
             root_ppn: frame.ppn,
+            asid: 0,
+            asid_handle: None,
             frames: vec![frame],
-        }
+        })
     }
-    /// from vpn to pte
+    /// from vpn to pte, for a huge page an entry of the page `vpn` in it
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
-        self.find_pte(vpn).map(|pte| pte.clone())
+        self.find_leaf(vpn).map(|(pte, level)| {
+            let offset = vpn.0 % level_pages(level);
+            PageTableEntry {
+                bits: pte.bits + (offset << 10),
+            }
+        })
     }
     pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
-        self.find_pte(va.clone().floor()).map(|pte| {
+        self.translate(va.clone().floor()).map(|pte| {
             let aligned_pa: PhysAddr = pte.ppn().into();
             let offset = va.page_offset();
             let aligned_pa_usize: usize = aligned_pa.into();
@@ -165,19 +351,68 @@ impl PageTable {
     }
 
     pub fn token(&self) -> usize {
-        8_usize << 60 | self.root_ppn.0
+        satp_mode() << 60 | self.asid << 44 | self.root_ppn.0
+    }
+    /// number of frames of the page tables themselves
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+/// Translate a user page for the kernel to access, the page must be a user page
+/// allowing `access`, or Invalid.
+/// A lazy or swapped out page of the current task is faulted in first,
+/// and a copy-on-write page to be written gets copied.
+/// Out of memory, some task is killed to make room, if it is the current one
+/// OutOfMemory is returned and the task dies on its way back to user space.
+fn translate_user_page(
+    page_table: &PageTable,
+    vpn: VirtPageNum,
+    access: MapPermission,
+) -> Result<PhysPageNum, FaultError> {
+    let needed = PTEFlags::from_bits(access.bits()).unwrap() | PTEFlags::V | PTEFlags::U;
+    loop {
+        if let Some(pte) = page_table.find_pte(vpn) {
+            if pte.flags().contains(needed) {
+                if access.contains(MapPermission::W) {
+                    // the kernel writes the page without the MMU knowing
+                    pte.mark_dirty();
+                }
+                return Ok(pte.ppn());
+            }
+        }
+        match fault_in_current(page_table.token(), vpn, access) {
+            Ok(()) => {}
+            Err(FaultError::OutOfMemory) => {
+                if out_of_memory() {
+                    return Err(FaultError::OutOfMemory);
+                }
+            }
+            Err(FaultError::Invalid) => return Err(FaultError::Invalid),
+        }
     }
 }
 
-pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&'static mut [u8]> {
+/// Translate the user buffer `[ptr, ptr + len)` page by page,
+/// the kernel writes to it if `access` has W.
+pub fn translated_byte_buffer(
+    token: usize,
+    ptr: *const u8,
+    len: usize,
+    access: MapPermission,
+) -> Result<Vec<&'static mut [u8]>, FaultError> {
     let page_table = PageTable::from_token(token); // read only page table
     let mut start = ptr as usize;
-    let end = start + len;
+    let end = start.checked_add(len).ok_or(FaultError::Invalid)?;
     let mut v: Vec<&'static mut [u8]> = Vec::new();
     while start < end {
         let start_va = VirtAddr::from(start);
+        if usize::from(start_va) != start {
+            // not a canonical address
+            return Err(FaultError::Invalid);
+        }
         let mut vpn = start_va.floor();
-        let ppn = page_table.translate(vpn).unwrap().ppn();
+        let ppn = translate_user_page(&page_table, vpn, access)?;
         vpn.step();
         let mut end_va: VirtAddr = vpn.into();
         end_va = end_va.min(VirtAddr::from(end));
@@ -188,48 +423,69 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
         }
         start = end_va.into();
     }
-    v
+    Ok(v)
 }
 
-/// translate a pointer to a mutable u8 Vec end with `\0` through page table to a `String`
-pub fn translated_str(token: usize, ptr: *const u8) -> String {
-    let page_table = PageTable::from_token(token); // read only page table
+/// why a string could not be copied from user space
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StrError {
+    Fault(FaultError),
+    /// no `\0` in the first USER_STR_MAX bytes
+    TooLong,
+}
+
+impl From<FaultError> for StrError {
+    fn from(err: FaultError) -> Self {
+        Self::Fault(err)
+    }
+}
+
+/// Copy a string ending with `\0` from user space, without the `\0`.
+pub fn translated_str(token: usize, ptr: *const u8) -> Result<String, StrError> {
     let mut string = String::new();
+    let mut copied = 0;
     let mut va = ptr as usize;
     loop {
-        let ch: u8 = *(page_table
-            .translate_va(VirtAddr::from(va))
-            .unwrap()
-            .get_mut());
-        if ch == 0 {
-            break;
-        } else {
-            string.push(ch as char);
-            va += 1;
+        // up to the end of the page, the next one may not be mapped
+        let len = PAGE_SIZE - VirtAddr::from(va).page_offset();
+        for buffer in translated_byte_buffer(token, va as *const u8, len, MapPermission::R)? {
+            for &ch in buffer.iter() {
+                if ch == 0 {
+                    return Ok(string);
+                }
+                copied += 1;
+                if copied == USER_STR_MAX {
+                    return Err(StrError::TooLong);
+                }
+                string.push(ch as char);
+            }
         }
+        va += len;
     }
-
-    string
 }
 
-pub fn translated_ref<T>(token: usize, ptr: *const T) -> &'static T {
-    let page_table = PageTable::from_token(token);
-    page_table
-        .translate_va(VirtAddr::from(ptr as usize))
-        .unwrap()
-        .get_ref()
+/// Copy a `T` from user space, it may cross pages and need not be aligned.
+pub fn copy_from_user<T: Copy>(token: usize, ptr: *const T) -> Result<T, FaultError> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let dst = value.as_mut_ptr() as *mut u8;
+    let mut copied = 0;
+    for buffer in translated_byte_buffer(token, ptr as *const u8, size_of::<T>(), MapPermission::R)? {
+        unsafe { core::ptr::copy_nonoverlapping(buffer.as_ptr(), dst.add(copied), buffer.len()) };
+        copied += buffer.len();
+    }
+    // all the bytes of the value are copied
+    Ok(unsafe { value.assume_init() })
 }
 
-///translate a generic through page table and return a mutable reference
-pub fn translated_refmut<T>(token: usize, ptr: *mut T) -> &'static mut T {
-    //println!("into translated_refmut!");
-    let page_table = PageTable::from_token(token);
-    let va = ptr as usize;
-    //println!("translated_refmut: before translate_va");
-    page_table
-        .translate_va(VirtAddr::from(va))
-        .unwrap()
-        .get_mut()
+/// Copy `value` to user space at `ptr`, which may cross pages and need not be aligned.
+pub fn copy_to_user<T: Copy>(token: usize, ptr: *mut T, value: &T) -> Result<(), FaultError> {
+    let src = value as *const T as *const u8;
+    let mut copied = 0;
+    for buffer in translated_byte_buffer(token, ptr as *const u8, size_of::<T>(), MapPermission::W)? {
+        unsafe { core::ptr::copy_nonoverlapping(src.add(copied), buffer.as_mut_ptr(), buffer.len()) };
+        copied += buffer.len();
+    }
+    Ok(())
 }
 
 /// UserBuffer is u8 slice array in user space
@@ -243,11 +499,8 @@ impl UserBuffer {
     }
 
     pub fn len(&self) -> usize {
-        self.buffers
-            .iter()
-            .map(|buffer| buffer.len())
-            .reduce(usize::wrapping_add)
-            .unwrap()
+        // no buffers at all for an empty one
+        self.buffers.iter().map(|buffer| buffer.len()).sum()
     }
 }
 
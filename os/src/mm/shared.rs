@@ -0,0 +1,157 @@
+//! Frames shared by several areas, of System V segments and file mappings.
+//!
+//! The frames are reference-counted through `Arc<SharedFrames>`, every area
+//! mapping them keeps a reference, so they are freed with the last one.
+//! All the mappings of a file share the frames of its pages, which are
+//! read in on first use and written back by the areas which dirtied them.
+//!
+//! easy-fs is never called with a task borrowed, which masks interrupts:
+//! the page fault path reads the page in before taking the task, and the
+//! write-backs are queued and done once the task is dropped.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use easy_fs::Inode;
+use lazy_static::lazy_static;
+
+use super::{
+    address::PhysPageNum,
+    frame_allocator::{frame_alloc, FrameTracker, OutOfMemory},
+};
+use crate::{config::PAGE_SIZE, fs::with_fs, sync::UPSafeCell};
+
+/// Frames shared by all the areas mapping them, freed with the last one.
+pub struct SharedFrames {
+    /// the size of a System V segment, a file has as many pages as it needs
+    pages: usize,
+    /// the file the pages come from, page `i` is at `i * PAGE_SIZE`
+    inode: Option<Arc<Inode>>,
+    frames: UPSafeCell<BTreeMap<usize, FrameTracker>>,
+}
+
+lazy_static! {
+    /// the frames of the mapped files, by where their disk inode is
+    static ref FILE_FRAMES: UPSafeCell<BTreeMap<(usize, usize), Weak<SharedFrames>>> =
+        unsafe { UPSafeCell::new(BTreeMap::new()) };
+}
+
+impl SharedFrames {
+    /// `pages` zeroed frames, None if out of memory
+    pub fn new(pages: usize) -> Option<Self> {
+        let mut frames = BTreeMap::new();
+        for i in 0..pages {
+            frames.insert(i, frame_alloc()?);
+        }
+        Some(Self {
+            pages,
+            inode: None,
+            frames: unsafe { UPSafeCell::new(frames) },
+        })
+    }
+    /// The frames of the pages of `inode`, read in when first used,
+    /// the same for all the mappings of the file.
+    pub fn of_file(inode: Arc<Inode>) -> Arc<Self> {
+        let mut files = FILE_FRAMES.exclusive_access();
+        let key = inode.location();
+        if let Some(frames) = files.get(&key).and_then(Weak::upgrade) {
+            return frames;
+        }
+        let frames = Arc::new(Self {
+            pages: 0,
+            inode: Some(inode),
+            frames: unsafe { UPSafeCell::new(BTreeMap::new()) },
+        });
+        files.insert(key, Arc::downgrade(&frames));
+        frames
+    }
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+    pub fn is_file(&self) -> bool {
+        self.inode.is_some()
+    }
+    /// the frame of page `index` if it is in memory
+    pub fn present(&self, index: usize) -> Option<PhysPageNum> {
+        self.frames.exclusive_access().get(&index).map(|frame| frame.ppn)
+    }
+    /// whether page `index` is from the file and still to be read in
+    pub fn unread(&self, index: usize) -> bool {
+        self.inode.is_some() && self.present(index).is_none()
+    }
+    /// Read page `index` from the file if it is not in memory, it stays
+    /// out if it is all past the end of the file.
+    /// Not to be called with a task borrowed, as for all easy-fs calls.
+    pub fn read_in(&self, index: usize) -> Result<(), OutOfMemory> {
+        let inode = match self.inode.as_ref() {
+            Some(inode) if self.present(index).is_none() => inode,
+            _ => return Ok(()),
+        };
+        let pos = index * PAGE_SIZE;
+        if pos >= with_fs(|| inode.size()) {
+            return Ok(());
+        }
+        // not holding the frames, the allocation may swap out pages
+        let frame = frame_alloc().ok_or(OutOfMemory)?;
+        // the part past the end of the file stays zero
+        with_fs(|| inode.read_at(pos, frame.ppn.get_bytes_array()));
+        // another task sharing the frames may have read it in meanwhile
+        self.frames.exclusive_access().entry(index).or_insert(frame);
+        Ok(())
+    }
+    /// Write page `index` back to the file, the part past the end
+    /// of the file is dropped rather than growing it.
+    fn write_back(&self, index: usize) {
+        let (inode, ppn) = match (self.inode.as_ref(), self.present(index)) {
+            (Some(inode), Some(ppn)) => (inode, ppn),
+            _ => return,
+        };
+        let pos = index * PAGE_SIZE;
+        with_fs(|| {
+            let len = inode.size().saturating_sub(pos).min(PAGE_SIZE);
+            inode.write_at(pos, &ppn.get_bytes_array()[..len]);
+        });
+    }
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        if let Some(inode) = self.inode.as_ref() {
+            let mut files = FILE_FRAMES.exclusive_access();
+            let key = inode.location();
+            // unless the file got new frames already
+            if files.get(&key).map_or(false, |frames| frames.strong_count() == 0) {
+                files.remove(&key);
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// the pages to write back, holding their frames until they are written
+    static ref WRITE_BACKS: UPSafeCell<Vec<(Arc<SharedFrames>, usize)>> =
+        unsafe { UPSafeCell::new(Vec::new()) };
+}
+
+/// Queue page `index` of `frames` to be written back to its file
+/// by the next `flush_write_backs`.
+pub fn queue_write_back(frames: &Arc<SharedFrames>, index: usize) {
+    if frames.is_file() {
+        WRITE_BACKS.exclusive_access().push((frames.clone(), index));
+    }
+}
+
+/// Write the queued pages back to their files,
+/// called with no task borrowed before returning to user space.
+pub fn flush_write_backs() {
+    loop {
+        // not holding the queue during the write
+        let next = WRITE_BACKS.exclusive_access().pop();
+        match next {
+            Some((frames, index)) => frames.write_back(index),
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! System V shared memory segments.
+//!
+//! A segment owns its frames through a reference-counted `SharedFrames`,
+//! every area attaching it keeps a reference, so removing the segment
+//! only frees the frames after the last detach.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use lazy_static::lazy_static;
+
+use super::shared::SharedFrames;
+use crate::sync::UPSafeCell;
+
+/// the key asking for a new segment nobody else can look up
+pub const IPC_PRIVATE: usize = 0;
+
+struct ShmSegment {
+    key: usize,
+    frames: Arc<SharedFrames>,
+}
+
+struct ShmTable {
+    segments: BTreeMap<usize, ShmSegment>,
+    next_id: usize,
+}
+
+lazy_static! {
+    static ref SHM_TABLE: UPSafeCell<ShmTable> = unsafe {
+        UPSafeCell::new(ShmTable {
+            segments: BTreeMap::new(),
+            next_id: 0,
+        })
+    };
+}
+
+/// the id of the segment with `key`, never found for IPC_PRIVATE
+pub fn shm_find(key: usize) -> Option<usize> {
+    if key == IPC_PRIVATE {
+        return None;
+    }
+    SHM_TABLE
+        .exclusive_access()
+        .segments
+        .iter()
+        .find(|(_, seg)| seg.key == key)
+        .map(|(id, _)| *id)
+}
+
+/// Create a segment of `pages` pages and return its id,
+/// None if there are not enough frames.
+pub fn shm_create(key: usize, pages: usize) -> Option<usize> {
+    // allocated before taking the table, it may swap out pages
+    let frames = Arc::new(SharedFrames::new(pages)?);
+    let mut table = SHM_TABLE.exclusive_access();
+    let id = table.next_id;
+    table.next_id += 1;
+    table.segments.insert(id, ShmSegment { key, frames });
+    Some(id)
+}
+
+pub fn shm_frames(id: usize) -> Option<Arc<SharedFrames>> {
+    SHM_TABLE
+        .exclusive_access()
+        .segments
+        .get(&id)
+        .map(|seg| seg.frames.clone())
+}
+
+/// Remove the segment from the table, the attached areas keep its frames.
+/// Return false if there is no such segment.
+pub fn shm_remove(id: usize) -> bool {
+    SHM_TABLE.exclusive_access().segments.remove(&id).is_some()
+}
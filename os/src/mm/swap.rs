@@ -0,0 +1,233 @@
+//! Swapping of anonymous user pages to the swap disk.
+//!
+//! Every present page of a lazy area is tracked here by its page table token
+//! and vpn, the frame belongs to the tracker instead of the `MapArea`.
+//! When no frame is left, a victim is chosen by the clock algorithm from
+//! the accessed bits of the PTEs, written to a slot of the swap disk unless
+//! the slot it came from is still clean, and its PTE becomes a swap entry
+//! pointing to the slot.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ops::Bound;
+use easy_fs::BLOCK_SZ;
+use lazy_static::lazy_static;
+
+use super::{
+    address::{PhysPageNum, VirtPageNum},
+    frame_allocator::FrameTracker,
+    page_table::{PageTable, PageTableEntry},
+};
+use crate::{
+    config::{PAGE_SIZE, SWAP_SIZE},
+    drivers::SWAP_DEVICE,
+    sync::UPSafeCell,
+};
+
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SZ;
+const SWAP_SLOTS: usize = SWAP_SIZE / PAGE_SIZE;
+
+/// a present page which can be swapped out
+struct SwapPage {
+    frame: FrameTracker,
+    /// the slot it was swapped in from, still holding the same data
+    /// until the page gets dirty
+    slot: Option<usize>,
+}
+
+/// 按页表 token 和虚拟页号记录的页
+type PageKey = (usize, VirtPageNum);
+
+struct SwapManager {
+    pages: BTreeMap<PageKey, SwapPage>,
+    /// the clock hand, the last page looked at
+    hand: Option<PageKey>,
+    /// page tables whose pages the kernel is accessing, with nesting counts
+    pinned: BTreeMap<usize, usize>,
+    // slots are allocated like frames
+    current_slot: usize,
+    recycled_slots: Vec<usize>,
+}
+
+impl SwapManager {
+    fn new() -> Self {
+        Self {
+            pages: BTreeMap::new(),
+            hand: None,
+            pinned: BTreeMap::new(),
+            current_slot: 0,
+            recycled_slots: Vec::new(),
+        }
+    }
+
+    fn alloc_slot(&mut self) -> Option<usize> {
+        if let Some(slot) = self.recycled_slots.pop() {
+            Some(slot)
+        } else if self.current_slot == SWAP_SLOTS {
+            None
+        } else {
+            self.current_slot += 1;
+            Some(self.current_slot - 1)
+        }
+    }
+
+    fn dealloc_slot(&mut self, slot: usize) {
+        assert!(slot < self.current_slot, "swap slot {} is not allocated", slot);
+        self.recycled_slots.push(slot);
+    }
+
+    /// the page after the clock hand, wrapping around
+    fn next_key(&self) -> Option<PageKey> {
+        let after = match self.hand {
+            Some(hand) => self
+                .pages
+                .range((Bound::Excluded(hand), Bound::Unbounded))
+                .next(),
+            None => None,
+        };
+        after.or_else(|| self.pages.iter().next()).map(|(key, _)| *key)
+    }
+
+    /// Second chance: the accessed bits are cleared as the hand passes,
+    /// the first page not accessed since the last round is the victim.
+    fn pick_victim(&mut self) -> Option<PageKey> {
+        // all the accessed bits are clear after one round
+        for _ in 0..2 * self.pages.len() {
+            let key = self.next_key()?;
+            self.hand = Some(key);
+            if self.pinned.contains_key(&key.0) {
+                continue;
+            }
+            let pte = pte_of(key);
+            if !pte.accessed() {
+                return Some(key);
+            }
+            pte.clear_accessed();
+            flush_page(key);
+        }
+        None
+    }
+}
+
+lazy_static! {
+    static ref SWAP_MANAGER: UPSafeCell<SwapManager> =
+        unsafe { UPSafeCell::new(SwapManager::new()) };
+}
+
+fn pte_of((token, vpn): PageKey) -> &'static mut PageTableEntry {
+    let pte: *mut PageTableEntry = PageTable::from_token(token).find_pte(vpn).unwrap();
+    // the entry is in a frame of the page table, not in the temporary
+    unsafe { &mut *pte }
+}
+
+/// flush the entry of the page in the address space it is in
+fn flush_page((token, vpn): PageKey) {
+    PageTable::from_token(token).flush(vpn);
+}
+
+fn write_slot(slot: usize, ppn: PhysPageNum) {
+    let bytes = ppn.get_bytes_array();
+    for (i, block) in bytes.chunks(BLOCK_SZ).enumerate() {
+        SWAP_DEVICE.write_block(slot * BLOCKS_PER_PAGE + i, block);
+    }
+}
+
+/// read the page in `slot` to the frame `ppn`
+pub fn read_slot(slot: usize, ppn: PhysPageNum) {
+    let bytes = ppn.get_bytes_array();
+    for (i, block) in bytes.chunks_mut(BLOCK_SZ).enumerate() {
+        SWAP_DEVICE.read_block(slot * BLOCKS_PER_PAGE + i, block);
+    }
+}
+
+pub fn init() {
+    // the disk allocates frames for its queue, which must not happen
+    // when swapping out for a lack of frames
+    lazy_static::initialize(&SWAP_DEVICE);
+}
+
+/// Track the present page `vpn` of the page table `token`,
+/// with the slot it was swapped in from.
+pub fn track(token: usize, vpn: VirtPageNum, frame: FrameTracker, slot: Option<usize>) {
+    let old = SWAP_MANAGER
+        .exclusive_access()
+        .pages
+        .insert((token, vpn), SwapPage { frame, slot });
+    assert!(old.is_none(), "page {:?} is tracked twice", vpn);
+}
+
+/// Stop tracking the page, freeing its frame and its slot
+pub fn untrack(token: usize, vpn: VirtPageNum) {
+    let mut manager = SWAP_MANAGER.exclusive_access();
+    let page = manager.pages.remove(&(token, vpn)).unwrap();
+    if let Some(slot) = page.slot {
+        manager.dealloc_slot(slot);
+    }
+}
+
+/// free the slot of a page still swapped out
+pub fn free_slot(slot: usize) {
+    SWAP_MANAGER.exclusive_access().dealloc_slot(slot);
+}
+
+/// Keep the pages of the page table `token` from being swapped out,
+/// while the kernel accesses them through their physical addresses.
+pub fn pin_user_pages(token: usize) {
+    *SWAP_MANAGER.exclusive_access().pinned.entry(token).or_insert(0) += 1;
+}
+
+pub fn unpin_user_pages(token: usize) {
+    let mut manager = SWAP_MANAGER.exclusive_access();
+    if let Some(count) = manager.pinned.get_mut(&token) {
+        *count -= 1;
+        if *count == 0 {
+            manager.pinned.remove(&token);
+        }
+    }
+}
+
+/// number of pages of the page table `token` in memory
+pub fn resident_pages(token: usize) -> usize {
+    SWAP_MANAGER
+        .exclusive_access()
+        .pages
+        .range((token, VirtPageNum(0))..(token + 1, VirtPageNum(0)))
+        .count()
+}
+
+/// forget the pins of a page table going away, its pages are all untracked
+pub fn forget(token: usize) {
+    SWAP_MANAGER.exclusive_access().pinned.remove(&token);
+}
+
+/// Swap out a page to free its frame,
+/// return false if there is no page to swap out or no free slot.
+pub fn swap_out() -> bool {
+    let mut manager = SWAP_MANAGER.exclusive_access();
+    let key = match manager.pick_victim() {
+        Some(key) => key,
+        None => return false,
+    };
+    let pte = pte_of(key);
+    let page = &manager.pages[&key];
+    let ppn = page.frame.ppn;
+    let slot = match page.slot {
+        // the slot still holds the same data
+        Some(slot) if !pte.dirty() => slot,
+        Some(slot) => {
+            write_slot(slot, ppn);
+            slot
+        }
+        None => match manager.alloc_slot() {
+            Some(slot) => {
+                write_slot(slot, ppn);
+                slot
+            }
+            None => return false,
+        },
+    };
+    *pte = PageTableEntry::swapped(slot);
+    flush_page(key);
+    // the frame is freed here
+    manager.pages.remove(&key);
+    true
+}
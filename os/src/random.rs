@@ -0,0 +1,39 @@
+//! Pseudo random numbers for the kernel, e.g. for address space layout
+//! randomization, not good enough for cryptography.
+//!
+//! A xorshift64* generator seeded from the real time clock and the timer,
+//! the timer is mixed in again on every draw for the jitter of when it happens.
+
+use lazy_static::lazy_static;
+
+use crate::{
+    sync::UPSafeCell,
+    timer::{get_realtime_ns, get_time},
+};
+
+lazy_static! {
+    static ref STATE: UPSafeCell<u64> = {
+        let seed = (get_realtime_ns() as u64).rotate_left(32) ^ get_time() as u64;
+        // xorshift gets stuck at zero
+        unsafe { UPSafeCell::new(seed | 1) }
+    };
+}
+
+/// a random number
+pub fn random() -> usize {
+    let mut state = STATE.exclusive_access();
+    let mut x = *state ^ (get_time() as u64).rotate_left(17);
+    if x == 0 {
+        x = 1;
+    }
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    x.wrapping_mul(0x2545_f491_4f6c_dd1d) as usize
+}
+
+/// a random number in `[0, bound)`, `bound` is not zero
+pub fn random_below(bound: usize) -> usize {
+    random() % bound
+}
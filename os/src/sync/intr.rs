@@ -0,0 +1,75 @@
+use core::cell::UnsafeCell;
+
+use riscv::register::sstatus;
+
+/// Nesting state of interrupt masking on the (only) hart.
+struct IntrMaskingInfo {
+    nested_level: usize,
+    // whether sstatus.SIE was set when the outermost guard was taken
+    sie_before_masking: bool,
+}
+
+struct IntrMaskingCell(UnsafeCell<IntrMaskingInfo>);
+
+// Only touched with interrupts disabled on a single hart
+unsafe impl Sync for IntrMaskingCell {}
+
+static INTR_MASKING_INFO: IntrMaskingCell = IntrMaskingCell(UnsafeCell::new(IntrMaskingInfo {
+    nested_level: 0,
+    sie_before_masking: false,
+}));
+
+/// Supervisor interrupts are disabled while any guard is alive,
+/// and restored to their previous state when the outermost one is dropped.
+pub struct IntrGuard {
+    // not Send, the guard belongs to the current control flow
+    _not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl IntrGuard {
+    pub fn new() -> Self {
+        let sie = intr_get();
+        intr_off();
+        let info = unsafe { &mut *INTR_MASKING_INFO.0.get() };
+        if info.nested_level == 0 {
+            info.sie_before_masking = sie;
+        }
+        info.nested_level += 1;
+        Self {
+            _not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for IntrGuard {
+    fn drop(&mut self) {
+        let info = unsafe { &mut *INTR_MASKING_INFO.0.get() };
+        assert!(info.nested_level > 0, "unbalanced IntrGuard");
+        info.nested_level -= 1;
+        if info.nested_level == 0 && info.sie_before_masking {
+            intr_on();
+        }
+    }
+}
+
+/// how many IntrGuards are alive, i.e. how deep we are in critical sections
+pub fn intr_nested_level() -> usize {
+    unsafe { (*INTR_MASKING_INFO.0.get()).nested_level }
+}
+
+/// whether supervisor interrupts are enabled
+pub fn intr_get() -> bool {
+    sstatus::read().sie()
+}
+
+pub fn intr_on() {
+    unsafe {
+        sstatus::set_sie();
+    }
+}
+
+pub fn intr_off() {
+    unsafe {
+        sstatus::clear_sie();
+    }
+}
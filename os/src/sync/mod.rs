@@ -1,3 +1,5 @@
+mod intr;
 mod up;
 
-pub use up::UPSafeCell;
+pub use intr::{intr_get, intr_nested_level, intr_off, intr_on, IntrGuard};
+pub use up::{UPRefMut, UPSafeCell};
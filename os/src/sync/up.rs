@@ -1,6 +1,13 @@
-use core::cell::{RefCell, RefMut};
+use core::{
+    cell::{RefCell, RefMut},
+    ops::{Deref, DerefMut},
+};
+
+use super::intr::IntrGuard;
 
 // Uni-Processor synchronization primitives
+// Interrupts are disabled while the inner data is borrowed,
+// so a trap taken in kernel mode never sees it half updated.
 pub struct UPSafeCell<T> {
     inner: RefCell<T>,
 }
@@ -16,7 +23,33 @@ impl <T> UPSafeCell<T> {
         }
     }
 
-    pub fn exclusive_access(&self) -> RefMut<T> {
-        self.inner.borrow_mut()
+    pub fn exclusive_access(&self) -> UPRefMut<'_, T> {
+        // mask interrupts before borrowing
+        let intr = IntrGuard::new();
+        UPRefMut {
+            inner: self.inner.borrow_mut(),
+            _intr: intr,
+        }
+    }
+}
+
+/// A mutable borrow of the data in a `UPSafeCell`.
+/// Fields are dropped in order, so the borrow is released
+/// before interrupts are enabled again.
+pub struct UPRefMut<'a, T> {
+    inner: RefMut<'a, T>,
+    _intr: IntrGuard,
+}
+
+impl<'a, T> Deref for UPRefMut<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.inner.deref()
+    }
+}
+
+impl<'a, T> DerefMut for UPRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner.deref_mut()
     }
 }
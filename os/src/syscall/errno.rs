@@ -0,0 +1,103 @@
+use core::fmt::{self, Debug, Formatter};
+
+use crate::mm::{FaultError, OutOfMemory};
+
+/// Error returned by a syscall handler.
+/// The values follow Linux errno, and reach user space negated in a0.
+#[repr(isize)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum SysError {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    E2BIG = 7,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENODEV = 19,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EPIPE = 32,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ETIMEDOUT = 110,
+}
+
+impl SysError {
+    /// the value put in a0, i.e. the negated errno
+    pub fn as_ret(self) -> isize {
+        -(self as isize)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::EPERM => "EPERM",
+            Self::ENOENT => "ENOENT",
+            Self::ESRCH => "ESRCH",
+            Self::EINTR => "EINTR",
+            Self::EIO => "EIO",
+            Self::E2BIG => "E2BIG",
+            Self::ENOEXEC => "ENOEXEC",
+            Self::EBADF => "EBADF",
+            Self::ECHILD => "ECHILD",
+            Self::EAGAIN => "EAGAIN",
+            Self::ENOMEM => "ENOMEM",
+            Self::EACCES => "EACCES",
+            Self::EFAULT => "EFAULT",
+            Self::EBUSY => "EBUSY",
+            Self::EEXIST => "EEXIST",
+            Self::ENODEV => "ENODEV",
+            Self::EISDIR => "EISDIR",
+            Self::EINVAL => "EINVAL",
+            Self::EMFILE => "EMFILE",
+            Self::EFBIG => "EFBIG",
+            Self::ENOSPC => "ENOSPC",
+            Self::ESPIPE => "ESPIPE",
+            Self::EPIPE => "EPIPE",
+            Self::ERANGE => "ERANGE",
+            Self::ENAMETOOLONG => "ENAMETOOLONG",
+            Self::ENOSYS => "ENOSYS",
+            Self::ETIMEDOUT => "ETIMEDOUT",
+        }
+    }
+}
+
+impl Debug for SysError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("{}({})", self.name(), *self as isize))
+    }
+}
+
+impl From<OutOfMemory> for SysError {
+    fn from(_: OutOfMemory) -> Self {
+        Self::ENOMEM
+    }
+}
+
+/// a bad user pointer, or no frames for the user pages it points to
+impl From<FaultError> for SysError {
+    fn from(err: FaultError) -> Self {
+        match err {
+            FaultError::Invalid => Self::EFAULT,
+            FaultError::OutOfMemory => Self::ENOMEM,
+        }
+    }
+}
+
+/// Every syscall handler returns this,
+/// `syscall` turns it into the value of a0.
+pub type SysResult = Result<isize, SysError>;
@@ -1,79 +1,79 @@
 use alloc::sync::Arc;
 
 use crate::{
-    fs::{make_pipe, open_file, OpenFlags},
-    mm::{translated_byte_buffer, translated_refmut, translated_str, UserBuffer},
+    fs::{make_pipe, open_file, File, OpenFlags},
+    mm::{
+        copy_to_user, translated_byte_buffer, translated_str, MapPermission, StrError, UserBuffer,
+    },
     task::{current_task, current_user_token},
 };
 
-pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
-    let token = current_user_token();
+use super::errno::{SysError, SysResult};
+
+/// get the file of fd in current task, or EBADF
+pub(super) fn current_file(fd: usize) -> Result<Arc<dyn File + Send + Sync>, SysError> {
     let task = current_task().unwrap();
     let inner = task.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-
-    if let Some(file) = &inner.fd_table[fd] {
-        let file = file.clone();
-        if !file.readable() {
-            return -1;
-        }
-        drop(inner);
-        file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
-    } else {
-        -1
-    }
+    inner
+        .fd_table
+        .get(fd)
+        .and_then(|file| file.clone())
+        .ok_or(SysError::EBADF)
 }
 
-pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> SysResult {
     let token = current_user_token();
-    let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
+    let file = current_file(fd)?;
+    if !file.readable() {
+        return Err(SysError::EBADF);
     }
+    let buffers = translated_byte_buffer(token, buf, len, MapPermission::W)?;
+    Ok(file.read(UserBuffer::new(buffers)) as isize)
+}
 
-    if let Some(file) = &inner.fd_table[fd] {
-        if !file.writable() {
-            return -1;
-        }
-        let file = file.clone();
-        drop(inner);
-        file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
-    } else {
-        -1
+pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> SysResult {
+    let token = current_user_token();
+    let file = current_file(fd)?;
+    if !file.writable() {
+        return Err(SysError::EBADF);
     }
+    let buffers = translated_byte_buffer(token, buf, len, MapPermission::R)?;
+    Ok(file.write(UserBuffer::new(buffers)) as isize)
 }
 
-pub fn sys_open(path: *const u8, flags: u32) -> isize {
+pub fn sys_open(path: *const u8, flags: u32) -> SysResult {
     let task = current_task().unwrap();
     let token = current_user_token();
-    let path = translated_str(token, path);
-    if let Some(inode) = open_file(&path, OpenFlags::from_bits(flags).unwrap()) {
+    let path = translated_str(token, path).map_err(|err| match err {
+        StrError::Fault(err) => SysError::from(err),
+        StrError::TooLong => SysError::ENAMETOOLONG,
+    })?;
+    let flags = OpenFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+    if let Some(inode) = open_file(&path, flags) {
         let mut inner = task.inner_exclusive_access();
         let fd = inner.alloc_fd();
         inner.fd_table[fd] = Some(inode);
-        fd as isize
+        Ok(fd as isize)
+    } else if flags.contains(OpenFlags::CREATE) {
+        // only fails when no inode can be allocated
+        Err(SysError::ENOSPC)
     } else {
-        -1
+        Err(SysError::ENOENT)
     }
 }
 
-pub fn sys_close(fd: usize) -> isize {
+pub fn sys_close(fd: usize) -> SysResult {
     let task = current_task().unwrap();
     let mut inner = task.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if inner.fd_table[fd].is_none() {
-        return -1;
-    }
-    inner.fd_table[fd].take();
-    0
+    inner
+        .fd_table
+        .get_mut(fd)
+        .and_then(|file| file.take())
+        .ok_or(SysError::EBADF)?;
+    Ok(0)
 }
 
-pub fn sys_pipe(pipe: *mut usize) -> isize {
+pub fn sys_pipe(pipe: *mut usize) -> SysResult {
     let task = current_task().unwrap();
     let token = current_user_token();
     let mut inner = task.inner_exclusive_access();
@@ -83,22 +83,23 @@ pub fn sys_pipe(pipe: *mut usize) -> isize {
     inner.fd_table[read_fd] = Some(pipe_read);
     let write_fd = inner.alloc_fd();
     inner.fd_table[write_fd] = Some(pipe_write);
+    // writing to user memory may fault in a page of the task
+    drop(inner);
 
-    *translated_refmut(token, pipe) = read_fd;
-    *translated_refmut(token, unsafe { pipe.add(1) }) = write_fd;
-    0
+    if let Err(err) = copy_to_user(token, pipe as *mut [usize; 2], &[read_fd, write_fd]) {
+        let mut inner = task.inner_exclusive_access();
+        inner.fd_table[read_fd] = None;
+        inner.fd_table[write_fd] = None;
+        return Err(err.into());
+    }
+    Ok(0)
 }
 
-pub fn sys_dup(fd: usize) -> isize {
+pub fn sys_dup(fd: usize) -> SysResult {
+    let file = current_file(fd)?;
     let task = current_task().unwrap();
     let mut inner = task.inner_exclusive_access();
-    if fd >= inner.fd_table.len() {
-        return -1;
-    }
-    if inner.fd_table[fd].is_none() {
-        return -1;
-    }
     let new_fd = inner.alloc_fd();
-    inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
-    new_fd as isize
+    inner.fd_table[new_fd] = Some(file);
+    Ok(new_fd as isize)
 }
@@ -0,0 +1,246 @@
+use crate::{
+    config::PAGE_SIZE,
+    mm::{is_user_range, MapPermission, SharedFrames, VirtAddr, VirtPageNum},
+    task::current_task,
+};
+
+use super::{
+    errno::{SysError, SysResult},
+    fs::current_file,
+};
+
+bitflags! {
+    pub struct ProtFlags: usize {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+bitflags! {
+    pub struct MapFlags: usize {
+        const SHARED = 1 << 0;
+        const PRIVATE = 1 << 1;
+        const FIXED = 1 << 4;
+        const ANONYMOUS = 1 << 5;
+    }
+}
+
+impl From<ProtFlags> for MapPermission {
+    fn from(prot: ProtFlags) -> Self {
+        let mut perm = MapPermission::U;
+        // a writable page must also be readable on RISC-V
+        if prot.intersects(ProtFlags::READ | ProtFlags::WRITE) {
+            perm |= MapPermission::R;
+        }
+        if prot.contains(ProtFlags::WRITE) {
+            perm |= MapPermission::W;
+        }
+        if prot.contains(ProtFlags::EXEC) {
+            perm |= MapPermission::X;
+        }
+        perm
+    }
+}
+
+const MS_ASYNC: usize = 1;
+const MS_INVALIDATE: usize = 2;
+const MS_SYNC: usize = 4;
+
+/// Map `len` bytes of zeroed private memory with MAP_ANONYMOUS, or of the file
+/// `fd` from `offset` otherwise, read in on page faults.
+/// All the mappings of a file share the frames of its pages, the dirty pages
+/// of a MAP_SHARED one are written back on msync, munmap and exit,
+/// a MAP_PRIVATE one gets its own copy of the pages it writes.
+/// Shared anonymous mappings are not supported.
+/// Without MAP_FIXED `addr` is a hint, with it a range overlapping
+/// existing mappings fails with EEXIST instead of replacing them.
+pub fn sys_mmap(
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> SysResult {
+    let prot = ProtFlags::from_bits(prot).ok_or(SysError::EINVAL)?;
+    let flags = MapFlags::from_bits(flags).ok_or(SysError::EINVAL)?;
+    let shared = flags.contains(MapFlags::SHARED);
+    if shared == flags.contains(MapFlags::PRIVATE)
+        || (shared && flags.contains(MapFlags::ANONYMOUS))
+    {
+        return Err(SysError::EINVAL);
+    }
+    // PROT_NONE pages have no valid page table entry, not supported yet
+    if len == 0 || prot.is_empty() || offset % PAGE_SIZE != 0 {
+        return Err(SysError::EINVAL);
+    }
+    let pages = len.checked_add(PAGE_SIZE - 1).ok_or(SysError::ENOMEM)? / PAGE_SIZE;
+    let frames = if flags.contains(MapFlags::ANONYMOUS) {
+        None
+    } else {
+        let file = current_file(fd)?;
+        let inode = file.inode().ok_or(SysError::ENODEV)?;
+        if !file.readable() || (shared && prot.contains(ProtFlags::WRITE) && !file.writable()) {
+            return Err(SysError::EACCES);
+        }
+        Some((SharedFrames::of_file(inode), file.writable()))
+    };
+
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let start_vpn = if flags.contains(MapFlags::FIXED) {
+        if VirtAddr::from(addr).page_offset() != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let end = pages
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| addr.checked_add(size))
+            .ok_or(SysError::ENOMEM)?;
+        if !is_user_range(addr, end) {
+            return Err(SysError::ENOMEM);
+        }
+        let start_vpn = VirtAddr::from(addr).floor();
+        if inner
+            .memory_set
+            .overlaps(start_vpn, VirtPageNum(start_vpn.0 + pages))
+        {
+            return Err(SysError::EEXIST);
+        }
+        start_vpn
+    } else {
+        let mmap_base = inner.memory_set.mmap_base();
+        let hint = if addr == 0 { mmap_base } else { addr };
+        inner
+            .memory_set
+            .find_free_range(VirtAddr::from(hint).floor(), pages)
+            .or_else(|| {
+                inner
+                    .memory_set
+                    .find_free_range(VirtAddr::from(mmap_base).floor(), pages)
+            })
+            .ok_or(SysError::ENOMEM)?
+    };
+    let start_va = VirtAddr::from(start_vpn);
+    let end_va = VirtAddr::from(VirtPageNum(start_vpn.0 + pages));
+    match frames {
+        None => inner
+            .memory_set
+            .insert_lazy_area(start_va, end_va, prot.into()),
+        Some((frames, writable)) if shared => inner.memory_set.insert_shared_area(
+            start_va,
+            pages,
+            frames,
+            offset / PAGE_SIZE,
+            writable,
+            prot.into(),
+        ),
+        Some((frames, _)) => inner.memory_set.insert_private_area(
+            start_va,
+            pages,
+            frames,
+            offset / PAGE_SIZE,
+            prot.into(),
+        ),
+    }?;
+    Ok(usize::from(start_va) as isize)
+}
+
+/// the pages of `[addr, addr + len)`, `addr` must be page aligned
+fn user_page_range(addr: usize, len: usize) -> Result<(VirtPageNum, VirtPageNum), SysError> {
+    if VirtAddr::from(addr).page_offset() != 0 {
+        return Err(SysError::EINVAL);
+    }
+    let end = addr
+        .checked_add(len)
+        .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+        .ok_or(SysError::EINVAL)?
+        / PAGE_SIZE
+        * PAGE_SIZE;
+    if !is_user_range(addr, end) {
+        return Err(SysError::EINVAL);
+    }
+    Ok((VirtAddr::from(addr).floor(), VirtAddr::from(end).ceil()))
+}
+
+/// Unmap the pages in `[addr, addr + len)`, whatever mapped them,
+/// mappings partly in the range keep their other pages.
+/// Fail with EINVAL to unmap only some of the heap, move the break for that.
+pub fn sys_munmap(addr: usize, len: usize) -> SysResult {
+    if len == 0 {
+        return Err(SysError::EINVAL);
+    }
+    let (start, end) = user_page_range(addr, len)?;
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if inner.memory_set.cuts_heap(start, end) {
+        return Err(SysError::EINVAL);
+    }
+    inner.memory_set.remove_range(start, end);
+    Ok(0)
+}
+
+/// Change the permission of the pages in `[addr, addr + len)` to `prot`,
+/// mappings partly in the range keep their permission for their other pages.
+/// Fail with ENOMEM if some pages in the range are not mapped, and with
+/// EACCES to make a shared mapping of a file opened read-only writable.
+/// The heap changes permission as a whole, EINVAL for only some of it.
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> SysResult {
+    let prot = ProtFlags::from_bits(prot).ok_or(SysError::EINVAL)?;
+    // PROT_NONE is not supported, as in mmap
+    if prot.is_empty() {
+        return Err(SysError::EINVAL);
+    }
+    let (start, end) = user_page_range(addr, len)?;
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if !inner.memory_set.is_mapped(start, end) {
+        return Err(SysError::ENOMEM);
+    }
+    if inner.memory_set.cuts_heap(start, end) {
+        return Err(SysError::EINVAL);
+    }
+    if prot.contains(ProtFlags::WRITE) && !inner.memory_set.may_write(start, end) {
+        return Err(SysError::EACCES);
+    }
+    inner.memory_set.protect_range(start, end, prot.into());
+    Ok(0)
+}
+
+/// Write the dirty pages of shared file mappings in `[addr, addr + len)`
+/// back to their files, always synchronously whatever the flags.
+/// Fail with ENOMEM if some pages in the range are not mapped.
+pub fn sys_msync(addr: usize, len: usize, flags: usize) -> SysResult {
+    if flags & !(MS_ASYNC | MS_INVALIDATE | MS_SYNC) != 0
+        || flags & (MS_ASYNC | MS_SYNC) == MS_ASYNC | MS_SYNC
+    {
+        return Err(SysError::EINVAL);
+    }
+    let (start, end) = user_page_range(addr, len)?;
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if !inner.memory_set.sync_range(start, end) {
+        return Err(SysError::ENOMEM);
+    }
+    Ok(0)
+}
+
+/// Move the program break to `addr` and return the new break.
+/// The heap starts right after the loaded image and is tracked by the memory set.
+/// On failure, or with `addr` 0, the break stays and is returned as it is.
+pub fn sys_brk(addr: usize) -> SysResult {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let heap_bottom = VirtAddr::from(inner.memory_set.heap().0).0;
+    if addr < heap_bottom {
+        return Ok(inner.program_brk as isize);
+    }
+    let heap_end = match addr.checked_add(PAGE_SIZE - 1) {
+        Some(end) => VirtAddr::from(end & !(PAGE_SIZE - 1)),
+        None => return Ok(inner.program_brk as isize),
+    };
+    if inner.memory_set.set_heap_end(heap_end.floor()) {
+        inner.program_brk = addr;
+    }
+    Ok(inner.program_brk as isize)
+}
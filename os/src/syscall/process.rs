@@ -2,37 +2,154 @@ use alloc::{string::String, sync::Arc, vec::Vec};
 
 use crate::{
     fs::{open_file, OpenFlags},
-    mm::{translated_ref, translated_refmut, translated_str},
+    mm::{copy_from_user, copy_to_user, elf_interpreter, translated_str, ElfLoadError, StrError},
     println,
+    sync::{intr_get, intr_off, intr_on},
     task::{
-        add_task, current_task, current_user_token, exit_current_and_run_next, pid2task,
-        suspend_current_and_run_next, SignalAction, SignalFlags, MAX_SIG,
+        add_task, block_current_and_run_next, current_task, current_user_token,
+        exit_current_and_run_next, pid2task, suspend_current_and_run_next, wakeup_task,
+        Personality, SignalAction, SignalFlags, MAX_SIG,
+    },
+    timer::{
+        add_timer, cancel_timer, get_realtime_ns, get_time, ticks_to_ns, us_to_ticks, TimeSpec, TimeVal,
+        CLOCK_MONOTONIC, CLOCK_REALTIME,
     },
-    timer::get_time_ms,
 };
 
+use super::errno::{SysError, SysResult};
+
 pub fn sys_exit(exit_code: i32) -> ! {
     println!("[kernel] Application exited with code {}", exit_code);
     exit_current_and_run_next(exit_code);
     unreachable!("Unreachable after sys_exit");
 }
 
-pub fn sys_yield() -> isize {
+pub fn sys_yield() -> SysResult {
     suspend_current_and_run_next();
-    0
+    Ok(0)
+}
+
+/// Block the current task for `ms` milliseconds,
+/// EINTR if it is killed before that.
+/// Sleep for `us` microseconds, EINTR if woken up earlier
+pub fn sys_sleep(us: usize) -> SysResult {
+    let deadline = get_time() + us_to_ticks(us);
+    // a timer interrupt must not wake the task up before it is blocked
+    let sie = intr_get();
+    intr_off();
+    let task = current_task().unwrap();
+    let sleeper = task.clone();
+    let timer = add_timer(deadline, move || {
+        sleeper.inner_exclusive_access().sleep_timer = None;
+        wakeup_task(sleeper);
+    });
+    task.inner_exclusive_access().sleep_timer = Some(timer);
+    drop(task);
+    block_current_and_run_next();
+    if sie {
+        intr_on();
+    }
+    if get_time() < deadline {
+        return Err(SysError::EINTR);
+    }
+    Ok(0)
 }
 
-pub fn sys_get_time() -> isize {
-    get_time_ms() as isize
+pub fn sys_clock_gettime(clock_id: usize, ts: *mut TimeSpec) -> SysResult {
+    let ns = match clock_id {
+        CLOCK_REALTIME => get_realtime_ns(),
+        CLOCK_MONOTONIC => ticks_to_ns(get_time()),
+        _ => return Err(SysError::EINVAL),
+    };
+    if ts.is_null() {
+        return Err(SysError::EFAULT);
+    }
+    copy_to_user(current_user_token(), ts, &TimeSpec::from_ns(ns))?;
+    Ok(0)
 }
 
-pub fn sys_getpid() -> isize {
-    current_task().unwrap().pid.0 as isize
+/// the timezone argument is obsolete and ignored
+pub fn sys_gettimeofday(tv: *mut TimeVal) -> SysResult {
+    if tv.is_null() {
+        return Err(SysError::EFAULT);
+    }
+    let now = TimeSpec::from_ns(get_realtime_ns());
+    let tv_value = TimeVal {
+        tv_sec: now.tv_sec,
+        tv_usec: now.tv_nsec / 1000,
+    };
+    copy_to_user(current_user_token(), tv, &tv_value)?;
+    Ok(0)
+}
+
+pub fn sys_getpid() -> SysResult {
+    Ok(current_task().unwrap().pid.0 as isize)
+}
+
+/// Set the personality flags and return the old ones,
+/// 0xffffffff only queries them.
+pub fn sys_personality(persona: usize) -> SysResult {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let old = inner.personality;
+    if persona != 0xffff_ffff {
+        inner.personality = u32::try_from(persona)
+            .ok()
+            .and_then(Personality::from_bits)
+            .ok_or(SysError::EINVAL)?;
+    }
+    Ok(old.bits() as isize)
+}
+
+/// the only resource with a limit
+const RLIMIT_STACK: usize = 3;
+
+/// struct rlimit of getrlimit and setrlimit
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RLimit {
+    pub rlim_cur: usize,
+    pub rlim_max: usize,
+}
+
+/// EINVAL for a resource other than RLIMIT_STACK
+pub fn sys_getrlimit(resource: usize, rlim: *mut RLimit) -> SysResult {
+    if resource != RLIMIT_STACK {
+        return Err(SysError::EINVAL);
+    }
+    let (rlim_cur, rlim_max) = current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .memory_set
+        .stack_limit();
+    let limit = RLimit { rlim_cur, rlim_max };
+    copy_to_user(current_user_token(), rlim, &limit)?;
+    Ok(0)
+}
+
+/// Set the soft and the hard limit of the stack, kept across fork and exec.
+/// EINVAL for another resource or a soft limit above the hard one,
+/// EPERM to raise the hard limit, as no task is privileged.
+pub fn sys_setrlimit(resource: usize, rlim: *const RLimit) -> SysResult {
+    if resource != RLIMIT_STACK {
+        return Err(SysError::EINVAL);
+    }
+    let limit = copy_from_user(current_user_token(), rlim)?;
+    if limit.rlim_cur > limit.rlim_max {
+        return Err(SysError::EINVAL);
+    }
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if limit.rlim_max > inner.memory_set.stack_limit().1 {
+        return Err(SysError::EPERM);
+    }
+    inner.memory_set.set_stack_limit(limit.rlim_cur, limit.rlim_max);
+    Ok(0)
 }
 
-pub fn sys_fork() -> isize {
+pub fn sys_fork() -> SysResult {
     let current_task = current_task().unwrap();
-    let new_task = current_task.fork();
+    let new_task = current_task.fork()?;
     let new_pid = new_task.pid.0;
     let new_trap_cx = new_task.inner_exclusive_access().get_trap_cx();
     // return value for child process
@@ -41,12 +158,15 @@ pub fn sys_fork() -> isize {
     new_trap_cx.x[10] = 0;
 
     add_task(new_task);
-    new_pid as isize
+    Ok(new_pid as isize)
 }
 
-pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
+pub fn sys_exec(path: *const u8, mut args: *const usize) -> SysResult {
     let token = current_user_token();
-    let path = translated_str(token, path);
+    let path = translated_str(token, path).map_err(|err| match err {
+        StrError::Fault(err) => SysError::from(err),
+        StrError::TooLong => SysError::ENAMETOOLONG,
+    })?;
 
     println!("os sys_exec: path = {}", path);
     println!("os sys_exec: args = {:x}", args as usize);
@@ -54,38 +174,58 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
     // translate args from user space
     let mut args_vec: Vec<String> = Vec::new();
     loop {
-        let arg_str_ptr = *translated_ref(token, args);
+        let arg_str_ptr = copy_from_user(token, args)?;
         if arg_str_ptr == 0 {
             break;
         }
-        args_vec.push(translated_str(token, arg_str_ptr as *const u8));
+        let arg = translated_str(token, arg_str_ptr as *const u8).map_err(|err| match err {
+            StrError::Fault(err) => SysError::from(err),
+            StrError::TooLong => SysError::E2BIG,
+        })?;
+        args_vec.push(arg);
         unsafe {
             args = args.add(1);
         }
     }
 
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
-        let task = current_task().unwrap();
-        let argc = args_vec.len();
-        task.exec(all_data.as_slice(), args_vec);
-        // return argc because cx.x[10] will be covered with it later
-        argc as isize
-    } else {
-        -1
-    }
+    let app_inode = open_file(path.as_str(), OpenFlags::RDONLY).ok_or(SysError::ENOENT)?;
+    let all_data = app_inode.read_all();
+    // a bad file is reported by exec below
+    let interp_data = match elf_interpreter(&all_data) {
+        Ok(Some(interp)) => Some(
+            open_file(interp, OpenFlags::RDONLY)
+                .ok_or(SysError::ENOENT)?
+                .read_all(),
+        ),
+        _ => None,
+    };
+    let task = current_task().unwrap();
+    let argc = args_vec.len();
+    task.exec(all_data.as_slice(), interp_data.as_deref(), args_vec).map_err(|err| {
+        println!("os sys_exec: failed to load {}: {:?}", path, err);
+        match err {
+            ElfLoadError::OutOfMemory => SysError::ENOMEM,
+            ElfLoadError::ArgsTooBig => SysError::E2BIG,
+            _ => SysError::ENOEXEC,
+        }
+    })?;
+    // return argc because cx.x[10] will be covered with it later
+    Ok(argc as isize)
 }
 
-pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
+/// Return the pid of an exited child,
+/// EAGAIN if the child exists but is still running,
+/// and ECHILD if there is no such child.
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> SysResult {
     let current_task = current_task().unwrap();
-    let mut current_task_inner = current_task.inner_exclusive_access();
+    let current_task_inner = current_task.inner_exclusive_access();
     if current_task_inner
         .children
         .iter()
         .find(|p| pid == -1 || pid as usize == p.getpid())
         .is_none()
     {
-        return -1;
+        return Err(SysError::ECHILD);
     }
 
     let pair = current_task_inner
@@ -96,99 +236,104 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
             p.inner_exclusive_access().is_zombie() && (pid == -1 || pid as usize == p.getpid())
         });
 
-    if let Some((index, _)) = pair {
-        let child = current_task_inner.children.remove(index);
-        assert_eq!(Arc::strong_count(&child), 1);
-
+    if let Some((index, child)) = pair {
         let found_pid = child.getpid();
         let exit_code = child.inner_exclusive_access().exit_code;
+        let token = current_task_inner.memory_set.token();
+        // writing to user memory may fault in a page of the task,
+        // and the child is only reaped once the exit code is written
+        drop(current_task_inner);
+        if !exit_code_ptr.is_null() {
+            copy_to_user(token, exit_code_ptr, &exit_code)?;
+        }
 
-        *translated_refmut(current_task_inner.memory_set.token(), exit_code_ptr) = exit_code;
-        found_pid as isize
+        let mut current_task_inner = current_task.inner_exclusive_access();
+        let child = current_task_inner.children.remove(index);
+        assert_eq!(Arc::strong_count(&child), 1);
+        Ok(found_pid as isize)
     } else {
-        -2
+        Err(SysError::EAGAIN)
     }
 }
 
-pub fn sys_kill(pid: usize, signum: i32) -> isize {
-    if let Some(task) = pid2task(pid) {
-        if let Some(flag) = SignalFlags::from_bits(1 << signum) {
-            let mut task_inner = task.inner_exclusive_access();
-            if task_inner.signals.contains(flag) {
-                return -1;
-            }
-            task_inner.signals.insert(flag);
-            0
-        } else {
-            -1
-        }
-    } else {
-        -1
+/// get the flag of a signal number, or EINVAL
+fn signal_flag(signum: i32) -> Result<SignalFlags, SysError> {
+    if signum < 0 || signum as usize > MAX_SIG {
+        return Err(SysError::EINVAL);
     }
+    SignalFlags::from_bits(1 << signum).ok_or(SysError::EINVAL)
 }
 
-pub fn sys_sigprocmask(mask: u32) -> isize {
-    if let Some(task) = current_task() {
-        let mut task_inner = task.inner_exclusive_access();
-        let old_mask = task_inner.signal_mask;
-        if let Some(flag) = SignalFlags::from_bits(mask) {
-            task_inner.signal_mask = flag;
-            return old_mask.bits() as isize;
+pub fn sys_kill(pid: usize, signum: i32) -> SysResult {
+    let task = pid2task(pid).ok_or(SysError::ESRCH)?;
+    let flag = signal_flag(signum)?;
+    let mut task_inner = task.inner_exclusive_access();
+    if task_inner.signals.contains(flag) {
+        // the same signal is still pending
+        return Err(SysError::EAGAIN);
+    }
+    task_inner.signals.insert(flag);
+    // a sleeping task is woken up to die
+    if flag == SignalFlags::SIGKILL {
+        if let Some(timer) = task_inner.sleep_timer.take() {
+            drop(task_inner);
+            if cancel_timer(timer) {
+                wakeup_task(task);
+            }
         }
-        -1
-    } else {
-        -1
     }
+    Ok(0)
 }
 
-pub fn sys_sigreturn() -> isize {
-    if let Some(task) = current_task() {
-        let mut inner = task.inner_exclusive_access();
-        inner.handling_sig = -1;
-        let trap_ctx = inner.get_trap_cx();
-        *trap_ctx = inner.trap_ctx_backup.unwrap();
-        // Here we return the value of a0 in the trap_ctx,
-        // otherwise it will be overwritten after we trap
-        // back to the original execution of the application.
-        trap_ctx.x[10] as isize
-    } else {
-        -1
-    }
+pub fn sys_sigprocmask(mask: u32) -> SysResult {
+    let task = current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    let old_mask = task_inner.signal_mask;
+    let flag = SignalFlags::from_bits(mask).ok_or(SysError::EINVAL)?;
+    task_inner.signal_mask = flag;
+    Ok(old_mask.bits() as isize)
 }
 
-fn check_sigaction_error(signal: SignalFlags, action: usize, old_action: usize) -> bool {
-    if action == 0
-        || old_action == 0
-        || signal == SignalFlags::SIGKILL
-        || signal == SignalFlags::SIGSTOP
-    {
-        true
-    } else {
-        false
-    }
+pub fn sys_sigreturn() -> SysResult {
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    // not inside a signal handler
+    let backup = inner.trap_ctx_backup.ok_or(SysError::EINVAL)?;
+    inner.handling_sig = -1;
+    let trap_ctx = inner.get_trap_cx();
+    *trap_ctx = backup;
+    // Here we return the value of a0 in the trap_ctx,
+    // otherwise it will be overwritten after we trap
+    // back to the original execution of the application.
+    Ok(trap_ctx.x[10] as isize)
 }
+
+/// Set the action of `signum` from `action` and return the previous one in `old_action`,
+/// either may be null to only query or only set it.
 pub fn sys_sigaction(
     signum: i32,
     action: *const SignalAction,
     old_action: *mut SignalAction,
-) -> isize {
+) -> SysResult {
     let token = current_user_token();
     let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access();
-    if signum as usize > MAX_SIG {
-        return -1;
+    let flag = signal_flag(signum)?;
+    if flag == SignalFlags::SIGKILL || flag == SignalFlags::SIGSTOP {
+        return Err(SysError::EINVAL);
     }
 
-    if let Some(flag) = SignalFlags::from_bits(1 << signum) {
-        if check_sigaction_error(flag, action as usize, old_action as usize) {
-            return -1;
-        }
-
-        let prev_action = inner.signal_actions.table[signum as usize];
-        *translated_refmut(token, old_action) = prev_action;
-        inner.signal_actions.table[signum as usize] = *translated_ref(token, action);
-        0
+    // user memory is accessed without holding the task, it may fault in pages
+    let new_action = if action.is_null() {
+        None
     } else {
-        -1
+        Some(copy_from_user(token, action)?)
+    };
+    if !old_action.is_null() {
+        let prev_action = task.inner_exclusive_access().signal_actions.table[signum as usize];
+        copy_to_user(token, old_action, &prev_action)?;
+    }
+    if let Some(new_action) = new_action {
+        task.inner_exclusive_access().signal_actions.table[signum as usize] = new_action;
     }
+    Ok(0)
 }
@@ -0,0 +1,114 @@
+use crate::{
+    config::PAGE_SIZE,
+    mm::{
+        is_user_range, shm_create, shm_find, shm_frames, shm_remove, MapPermission, VirtAddr,
+        VirtPageNum, IPC_PRIVATE,
+    },
+    task::current_task,
+};
+
+use super::errno::{SysError, SysResult};
+
+bitflags! {
+    /// the flags of shmget, the permission bits below them are ignored
+    pub struct ShmGetFlags: usize {
+        const IPC_CREAT = 0o1000;
+        const IPC_EXCL = 0o2000;
+    }
+}
+
+const SHM_RDONLY: usize = 0o10000;
+const IPC_RMID: usize = 0;
+
+/// Get the segment with `key`, creating one of `size` bytes with IPC_CREAT.
+/// IPC_PRIVATE always creates a new segment.
+pub fn sys_shmget(key: usize, size: usize, shmflg: usize) -> SysResult {
+    let flags = ShmGetFlags::from_bits_truncate(shmflg);
+    let pages = size.checked_add(PAGE_SIZE - 1).ok_or(SysError::EINVAL)? / PAGE_SIZE;
+    if let Some(id) = shm_find(key) {
+        if flags.contains(ShmGetFlags::IPC_CREAT | ShmGetFlags::IPC_EXCL) {
+            return Err(SysError::EEXIST);
+        }
+        if pages > shm_frames(id).unwrap().pages() {
+            return Err(SysError::EINVAL);
+        }
+        return Ok(id as isize);
+    }
+    if key != IPC_PRIVATE && !flags.contains(ShmGetFlags::IPC_CREAT) {
+        return Err(SysError::ENOENT);
+    }
+    if pages == 0 {
+        return Err(SysError::EINVAL);
+    }
+    let id = shm_create(key, pages).ok_or(SysError::ENOMEM)?;
+    Ok(id as isize)
+}
+
+/// Attach the segment at `addr`, or where mmap would put it if `addr` is 0.
+pub fn sys_shmat(shmid: usize, addr: usize, shmflg: usize) -> SysResult {
+    let frames = shm_frames(shmid).ok_or(SysError::EINVAL)?;
+    let pages = frames.pages();
+    let mut perm = MapPermission::U | MapPermission::R;
+    if shmflg & SHM_RDONLY == 0 {
+        perm |= MapPermission::W;
+    }
+
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    let start_vpn = if addr != 0 {
+        if VirtAddr::from(addr).page_offset() != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let end = addr
+            .checked_add(pages * PAGE_SIZE)
+            .ok_or(SysError::EINVAL)?;
+        let start_vpn = VirtAddr::from(addr).floor();
+        if !is_user_range(addr, end)
+            || inner
+                .memory_set
+                .overlaps(start_vpn, VirtPageNum(start_vpn.0 + pages))
+        {
+            return Err(SysError::EINVAL);
+        }
+        start_vpn
+    } else {
+        let mmap_base = inner.memory_set.mmap_base();
+        inner
+            .memory_set
+            .find_free_range(VirtAddr::from(mmap_base).floor(), pages)
+            .ok_or(SysError::ENOMEM)?
+    };
+    let start_va = VirtAddr::from(start_vpn);
+    inner
+        .memory_set
+        .insert_shared_area(start_va, pages, frames, 0, true, perm)?;
+    Ok(usize::from(start_va) as isize)
+}
+
+/// Detach the segment attached at `addr`.
+pub fn sys_shmdt(addr: usize) -> SysResult {
+    if VirtAddr::from(addr).page_offset() != 0 {
+        return Err(SysError::EINVAL);
+    }
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    if !inner
+        .memory_set
+        .remove_shared_area(VirtAddr::from(addr).floor())
+    {
+        return Err(SysError::EINVAL);
+    }
+    Ok(0)
+}
+
+/// Only IPC_RMID is supported, `buf` is ignored.
+/// The segment is freed once the last process detaches it.
+pub fn sys_shmctl(shmid: usize, cmd: usize, _buf: usize) -> SysResult {
+    if cmd != IPC_RMID {
+        return Err(SysError::EINVAL);
+    }
+    if !shm_remove(shmid) {
+        return Err(SysError::EINVAL);
+    }
+    Ok(0)
+}
@@ -3,6 +3,7 @@ use core::panic;
 use alloc::{
     collections::{btree_map::BTreeMap, vec_deque::VecDeque},
     sync::Arc,
+    vec::Vec,
 };
 use lazy_static::lazy_static;
 
@@ -53,6 +54,11 @@ pub fn pid2task(pid: usize) -> Option<Arc<TaskControlBlock>> {
     map.get(&pid).map(Arc::clone)
 }
 
+/// all the tasks not exited yet
+pub fn all_tasks() -> Vec<Arc<TaskControlBlock>> {
+    PID2TCB.exclusive_access().values().cloned().collect()
+}
+
 pub fn remove_from_pid2task(pid: usize) {
     let mut map = PID2TCB.exclusive_access();
     if map.remove(&pid).is_none() {
@@ -1,12 +1,12 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
 use context::TaskContext;
 use lazy_static::lazy_static;
-use manager::remove_from_pid2task;
+use manager::{all_tasks, remove_from_pid2task};
 use processor::{schedule, take_current_task};
-use task::TaskControlBlock;
 
 use crate::{
     fs::{open_file, OpenFlags},
+    mm::{flush_write_backs, frame_stats, heap_stats, FaultError, MapPermission, VirtPageNum},
     println,
     sbi::shutdown,
 };
@@ -23,8 +23,13 @@ mod task;
 pub use action::{SignalAction, SignalActions};
 pub use manager::add_task;
 pub use manager::pid2task;
-pub use processor::{current_task, current_trap_cx, current_user_token, run_tasks};
+pub use pid::{kernel_stack_guard_owner, kernel_stack_owner, kernel_stack_position};
+pub use processor::{
+    current_task, current_trap_cx, current_user_token, preempt_current, preempt_enabled,
+    preempt_point, run_tasks, PreemptGuard,
+};
 pub use signal::{SignalFlags, MAX_SIG};
+pub use task::{Personality, TaskControlBlock};
 
 pub fn suspend_current_and_run_next() {
     let current_task = take_current_task().unwrap();
@@ -38,6 +43,25 @@ pub fn suspend_current_and_run_next() {
     schedule(current_task_cx_ptr);
 }
 
+/// Switch away from the current task without putting it back to the ready queue,
+/// someone holding it will call `wakeup_task` later.
+pub fn block_current_and_run_next() {
+    let current_task = take_current_task().unwrap();
+
+    let mut current_task_inner = current_task.inner_exclusive_access();
+    let current_task_cx_ptr = &mut current_task_inner.task_cx as *mut context::TaskContext;
+    current_task_inner.task_status = task::TaskStatus::Blocked;
+    drop(current_task_inner);
+
+    schedule(current_task_cx_ptr);
+}
+
+/// make a blocked task ready again
+pub fn wakeup_task(task: Arc<TaskControlBlock>) {
+    task.inner_exclusive_access().task_status = task::TaskStatus::Ready;
+    add_task(task);
+}
+
 pub const IDLE_PID: usize = 0;
 
 pub fn exit_current_and_run_next(exit_code: i32) {
@@ -46,6 +70,16 @@ pub fn exit_current_and_run_next(exit_code: i32) {
     let pid = task.getpid();
     if pid == IDLE_PID {
         println!("The idle task exit with exit_code {}", exit_code);
+        let frames = frame_stats();
+        println!(
+            "[kernel] frames: {} total, {} free, {} used at peak",
+            frames.total, frames.free, frames.peak
+        );
+        let heap = heap_stats();
+        println!(
+            "[kernel] heap: {} bytes total, {} used, {} used at peak",
+            heap.total, heap.used, heap.peak
+        );
         if exit_code != 0 {
             shutdown(true);
         } else {
@@ -54,6 +88,12 @@ pub fn exit_current_and_run_next(exit_code: i32) {
     }
 
     remove_from_pid2task(task.getpid());
+    // the dirty pages of shared file mappings are written back while the task
+    // can still be rescheduled, as easy-fs may do it
+    task.inner_exclusive_access()
+        .memory_set
+        .recycle_data_pages();
+    flush_write_backs();
     let mut inner = task.inner_exclusive_access();
     inner.task_status = task::TaskStatus::Zombie;
     inner.exit_code = exit_code;
@@ -69,7 +109,6 @@ pub fn exit_current_and_run_next(exit_code: i32) {
     }
 
     inner.children.clear();
-    inner.memory_set.recycle_data_pages();
     drop(inner);
     drop(task);
 
@@ -89,6 +128,79 @@ pub fn add_initproc() {
     add_task(INITPROC.clone());
 }
 
+/// Fault in the page `vpn` of the current task if its address space is `token`,
+/// Invalid if there is no lazy page allowing `access`.
+pub fn fault_in_current(
+    token: usize,
+    vpn: VirtPageNum,
+    access: MapPermission,
+) -> Result<(), FaultError> {
+    let task = current_task().ok_or(FaultError::Invalid)?;
+    let mut inner = task.inner_exclusive_access();
+    if inner.memory_set.token() != token {
+        return Err(FaultError::Invalid);
+    }
+    // a page of a file is read in with the task dropped, not to call easy-fs
+    // with interrupts masked
+    if let Some((frames, index)) = inner.memory_set.file_page(vpn) {
+        drop(inner);
+        frames.read_in(index)?;
+        inner = task.inner_exclusive_access();
+        if inner.memory_set.token() != token {
+            return Err(FaultError::Invalid);
+        }
+    }
+    let sp = inner.get_trap_cx().x[2];
+    inner.memory_set.handle_page_fault(vpn, access, sp)
+}
+
+/// OOM killer, called when no frame is left even after swapping out.
+/// The task with the most resident frames gets SIGKILL, and the others run
+/// until it exits and frees them, then the caller tries again.
+/// Return true if the current task is the one to die, which is up to the caller.
+pub fn out_of_memory() -> bool {
+    let current = current_task().unwrap();
+    // blocked tasks would not handle the signal until woken up
+    let candidates: Vec<_> = all_tasks()
+        .into_iter()
+        .filter(|task| {
+            task.getpid() != IDLE_PID
+                && task.inner_exclusive_access().task_status != task::TaskStatus::Blocked
+        })
+        .collect();
+    let dying = |task: &Arc<TaskControlBlock>| {
+        task.inner_exclusive_access()
+            .signals
+            .contains(SignalFlags::SIGKILL)
+    };
+    if dying(&current) {
+        return true;
+    }
+    // someone is dying already, wait for its frames
+    if !candidates.iter().any(dying) {
+        let victim = match candidates
+            .iter()
+            .max_by_key(|task| task.inner_exclusive_access().memory_set.resident_frames())
+        {
+            Some(victim) => victim,
+            None => panic!("out of memory with nothing to kill"),
+        };
+        println!(
+            "[kernel] out of memory, killing pid {} with {} frames",
+            victim.getpid(),
+            victim.inner_exclusive_access().memory_set.resident_frames()
+        );
+        victim.inner_exclusive_access().signals |= SignalFlags::SIGKILL;
+        if Arc::ptr_eq(victim, &current) {
+            return true;
+        }
+    }
+    drop(candidates);
+    drop(current);
+    suspend_current_and_run_next();
+    false
+}
+
 pub fn check_signals_error_of_current() -> Option<(i32, &'static str)> {
     let task = current_task().unwrap();
     let task_inner = task.inner_exclusive_access();
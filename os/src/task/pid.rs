@@ -2,8 +2,8 @@ use alloc::vec::Vec;
 use lazy_static::lazy_static;
 
 use crate::{
-    config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE},
-    mm::{MapPermission, VirtAddr, KERNEL_SPACE},
+    config::{KERNEL_STACK_SIZE, MEMORY_END, PAGE_SIZE, TRAMPOLINE},
+    mm::{MapPermission, OutOfMemory, VirtAddr, KERNEL_SPACE},
     sync::UPSafeCell,
 };
 
@@ -75,8 +75,28 @@ pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
     (bottom, top)
 }
 
+/// Return the pid whose kernel stack `sp` points into.
+/// `sp` may be in the guard page below the stack after an overflow.
+pub fn kernel_stack_owner(sp: usize) -> usize {
+    (TRAMPOLINE - sp) / (KERNEL_STACK_SIZE + PAGE_SIZE)
+}
+
+/// Return the pid whose kernel stack guard page contains `addr`, if any.
+pub fn kernel_stack_guard_owner(addr: usize) -> Option<usize> {
+    if addr >= TRAMPOLINE || addr < MEMORY_END {
+        return None;
+    }
+    let offset = TRAMPOLINE - addr - 1;
+    let stride = KERNEL_STACK_SIZE + PAGE_SIZE;
+    if offset % stride >= KERNEL_STACK_SIZE {
+        Some(offset / stride)
+    } else {
+        None
+    }
+}
+
 impl KernelStack {
-    pub fn new(pid_handle: &PidHandle) -> Self {
+    pub fn new(pid_handle: &PidHandle) -> Result<Self, OutOfMemory> {
         let pid = pid_handle.0;
         // map kernel stack
         let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(pid);
@@ -84,8 +104,8 @@ impl KernelStack {
             kernel_stack_bottom.into(),
             kernel_stack_top.into(),
             MapPermission::R | MapPermission::W,
-        );
-        KernelStack { pid }
+        )?;
+        Ok(KernelStack { pid })
     }
 
     // push a value on the highest address of the kernel stack
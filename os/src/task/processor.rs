@@ -1,7 +1,13 @@
 use alloc::sync::Arc;
 use lazy_static::lazy_static;
+use riscv::asm::wfi;
 
-use crate::{sync::UPSafeCell, trap::TrapContext};
+use crate::{
+    config::TICKLESS_IDLE,
+    sync::{intr_get, intr_off, intr_on, UPSafeCell},
+    timer::{set_idle_trigger, set_next_trigger},
+    trap::TrapContext,
+};
 
 use super::{
     context::TaskContext,
@@ -17,6 +23,10 @@ pub struct Processor {
     // the idle task is the task that the processor runs when there is no other task to run
     // mostly it is the loop in run_tasks
     idle_task_cx: TaskContext,
+    // kernel preemption is disabled while it is not zero
+    preempt_count: usize,
+    // a timer interrupt came while preemption was disabled
+    need_resched: bool,
 }
 
 impl Processor {
@@ -24,6 +34,8 @@ impl Processor {
         Processor {
             current: None,
             idle_task_cx: TaskContext::zero_init(),
+            preempt_count: 0,
+            need_resched: false,
         }
     }
 
@@ -47,9 +59,15 @@ lazy_static! {
 }
 
 pub fn run_tasks() -> ! {
+    let mut tick_stopped = false;
     loop {
         let mut processor = PROCESSOR.exclusive_access();
         if let Some(next_task) = fetch_task() {
+            if tick_stopped {
+                // the timer may only be set for the sleeping tasks, restart the tick
+                set_next_trigger();
+                tick_stopped = false;
+            }
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             let mut next_task_inner = next_task.inner_exclusive_access();
             let next_task_cx_ptr = &mut next_task_inner.task_cx as *mut TaskContext;
@@ -65,10 +83,30 @@ pub fn run_tasks() -> ! {
             unsafe {
                 switch::__switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
+        } else {
+            drop(processor);
+            if TICKLESS_IDLE {
+                set_idle_trigger();
+                tick_stopped = true;
+            }
+            wait_for_interrupt();
         }
     }
 }
 
+/// Nothing to run, sleep until an interrupt comes and handle it.
+/// The idle task runs with interrupts disabled, wfi still wakes up
+/// on a pending interrupt, so none comes in between checking the
+/// ready queue and sleeping and gets lost.
+fn wait_for_interrupt() {
+    unsafe {
+        wfi();
+    }
+    // take the pending interrupt
+    intr_on();
+    intr_off();
+}
+
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
     PROCESSOR.exclusive_access().take_current()
 }
@@ -96,9 +134,77 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
     let mut processor = PROCESSOR.exclusive_access();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
+    processor.need_resched = false;
     drop(processor);
 
+    // the idle task runs with interrupts disabled,
+    // restore them when this task is switched back
+    let sie = intr_get();
+    intr_off();
     unsafe {
         switch::__switch(switched_task_cx_ptr, idle_task_cx_ptr);
     }
+    if sie {
+        intr_on();
+    }
+}
+
+/// Kernel preemption is disabled while a `PreemptGuard` is alive.
+/// Unlike `UPSafeCell`, interrupts stay enabled, and a timer interrupt only
+/// marks the task to be rescheduled at the next `preempt_point`.
+/// The guard must not be held across a voluntary switch.
+pub struct PreemptGuard {
+    _not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        PROCESSOR.exclusive_access().preempt_count += 1;
+        Self {
+            _not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        PROCESSOR.exclusive_access().preempt_count -= 1;
+    }
+}
+
+/// whether the current control flow can be preempted
+pub fn preempt_enabled() -> bool {
+    PROCESSOR.exclusive_access().preempt_count == 0
+}
+
+/// Switch to the next task if a reschedule was requested
+/// while preemption was disabled. Called between steps of long kernel work.
+pub fn preempt_point() {
+    let resched = {
+        let processor = PROCESSOR.exclusive_access();
+        processor.current.is_some() && processor.need_resched && processor.preempt_count == 0
+    };
+    if resched {
+        super::suspend_current_and_run_next();
+    }
+}
+
+/// Called on a timer interrupt taken in kernel mode.
+/// Switch away from the current task if it can be preempted,
+/// otherwise leave it to the next `preempt_point`.
+pub fn preempt_current() {
+    let preemptible = {
+        let mut processor = PROCESSOR.exclusive_access();
+        if processor.current.is_none() {
+            // idle loop, or the task is being switched out already
+            return;
+        }
+        if processor.preempt_count > 0 {
+            processor.need_resched = true;
+        }
+        processor.preempt_count == 0
+    };
+    if preemptible {
+        super::suspend_current_and_run_next();
+    }
 }
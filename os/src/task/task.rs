@@ -1,5 +1,3 @@
-use core::cell::RefMut;
-
 use alloc::{
     string::String,
     sync::{Arc, Weak},
@@ -8,10 +6,14 @@ use alloc::{
 };
 
 use crate::{
-    config::TRAP_CONTEXT,
+    config::{ASLR, TRAP_CONTEXT, USER_STACK_LIMIT},
     fs::{File, Stdin, Stdout},
-    mm::{translated_refmut, MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE},
-    sync::UPSafeCell,
+    mm::{
+        pin_user_pages, unpin_user_pages, ElfLoadError, MemorySet, OutOfMemory, PhysPageNum,
+        VirtAddr, KERNEL_SPACE,
+    },
+    sync::{UPRefMut, UPSafeCell},
+    timer::TimerId,
     trap::{context::TrapContext, trap_handler},
 };
 
@@ -21,10 +23,20 @@ use super::{
     SignalActions, SignalFlags,
 };
 
+bitflags! {
+    /// the flags of personality(2), kept across fork and exec
+    pub struct Personality: u32 {
+        /// exec does not randomize the address space layout
+        const ADDR_NO_RANDOMIZE = 0x0040000;
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TaskStatus {
     Ready,
     Running,
+    // waiting for an event, not in the ready queue
+    Blocked,
     Zombie,
     // Exited removed
     // because at now, a process exited the PCB will be freed
@@ -43,7 +55,8 @@ pub struct TaskControlBlockInner {
     pub task_cx: TaskContext,
     pub memory_set: MemorySet,    // the memory space mapping of the task
     pub trap_cx_ppn: PhysPageNum, // reserved for trap handler
-    pub base_size: usize,         // size for loading elf
+    pub base_size: usize,         // size for loading elf, the heap starts here
+    pub program_brk: usize,       // end of the heap, moved by brk
     pub parent: Option<Weak<TaskControlBlock>>, // parent task weak reference
     pub children: Vec<Arc<TaskControlBlock>>, // children task owned reference
     pub exit_code: i32,           // exit code for waitpid
@@ -59,15 +72,20 @@ pub struct TaskControlBlockInner {
     // if the task is frozen by a signal
     pub frozen: bool,
     pub trap_ctx_backup: Option<TrapContext>,
+    pub personality: Personality,
+    /// the timer waking the task up from sleep, cancelled if it is killed
+    pub sleep_timer: Option<TimerId>,
 }
 
 impl TaskControlBlock {
-    pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
+    pub fn inner_exclusive_access(&self) -> UPRefMut<'_, TaskControlBlockInner> {
         self.inner.exclusive_access()
     }
     pub fn new(elf_data: &[u8]) -> Self {
         // memory_set with elf program headers/trampoline/trap context/user stack
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let (memory_set, user_sp, entry_point, image_end, _) =
+            MemorySet::from_elf(elf_data, None, ASLR, USER_STACK_LIMIT)
+                .expect("failed to load initproc");
 
         // trap context is const in virtual memory
         // we are in kernel space, so we should use translate
@@ -79,7 +97,7 @@ impl TaskControlBlock {
         let task_status = TaskStatus::Ready;
 
         let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack = KernelStack::new(&pid_handle).expect("no frames for initproc");
         let kernel_stack_top = kernel_stack.get_top();
 
         let task_control_block = Self {
@@ -91,7 +109,8 @@ impl TaskControlBlock {
                     task_cx: TaskContext::goto_trap_return(kernel_stack_top),
                     memory_set,
                     trap_cx_ppn,
-                    base_size: user_sp, // user_sp is the top of user stack
+                    base_size: image_end,
+                    program_brk: image_end,
                     parent: None,
                     children: Vec::new(),
                     exit_code: 0,
@@ -107,6 +126,8 @@ impl TaskControlBlock {
                     killed: false,
                     frozen: false,
                     trap_ctx_backup: None,
+                    personality: Personality::empty(),
+                    sleep_timer: None,
                 })
             },
         };
@@ -124,43 +145,64 @@ impl TaskControlBlock {
         task_control_block
     }
 
-    pub fn exec(&self, elf_data: &[u8], args: Vec<String>) {
+    /// Replace the address space with a new elf image,
+    /// `interp_data` is the dynamic loader it names in PT_INTERP.
+    /// On error the task is left untouched.
+    pub fn exec(
+        &self,
+        elf_data: &[u8],
+        interp_data: Option<&[u8]>,
+        args: Vec<String>,
+    ) -> Result<(), ElfLoadError> {
         // init a new memory set for the new elf
-        let (memory_set, mut user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        // the personality and the stack limit are kept across exec
+        let (randomize, (stack_limit, stack_limit_max)) = {
+            let inner = self.inner_exclusive_access();
+            (
+                ASLR && !inner.personality.contains(Personality::ADDR_NO_RANDOMIZE),
+                inner.memory_set.stack_limit(),
+            )
+        };
+        let (mut memory_set, mut user_sp, entry_point, image_end, auxv) =
+            MemorySet::from_elf(elf_data, interp_data, randomize, stack_limit)?;
+        memory_set.set_stack_limit(stack_limit, stack_limit_max);
         // trap context in new memory set
         let trap_cx_ppn = memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
             .unwrap()
             .ppn();
 
-        // push arguments on user stack
-        user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
-        let argv_base = user_sp;
-        let mut argv: Vec<_> = (0..=args.len())
-            .map(|arg| {
-                translated_refmut(
-                    memory_set.token(),
-                    (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize,
-                )
-            })
+        // push arguments on user stack, with the auxiliary vector after argv,
+        // the memory set is not active so they are written through it
+        let auxv_bytes: Vec<u8> = auxv
+            .iter()
+            .flat_map(|&(key, value)| [key, value])
+            .flat_map(|word| word.to_ne_bytes())
             .collect();
-        *argv[args.len()] = 0;
-        for i in 0..args.len() {
-            user_sp -= args[i].len() + 1;
-            *argv[i] = user_sp;
-            let mut p = user_sp;
-            for c in args[i].as_bytes() {
-                *translated_refmut(memory_set.token(), p as *mut u8) = *c;
-                p += 1;
-            }
-            *translated_refmut(memory_set.token(), p as *mut u8) = 0;
+        user_sp -= (args.len() + 1) * core::mem::size_of::<usize>() + auxv_bytes.len();
+        let argv_base = user_sp;
+        memory_set.write_user(
+            argv_base + (args.len() + 1) * core::mem::size_of::<usize>(),
+            &auxv_bytes,
+        )?;
+        let mut argv: Vec<usize> = Vec::new();
+        for arg in args.iter() {
+            user_sp -= arg.len() + 1;
+            argv.push(user_sp);
+            memory_set.write_user(user_sp, arg.as_bytes())?;
+            memory_set.write_user(user_sp + arg.len(), &[0])?;
         }
+        argv.push(0);
+        let argv_bytes: Vec<u8> = argv.iter().flat_map(|p| p.to_ne_bytes()).collect();
+        memory_set.write_user(argv_base, &argv_bytes)?;
         // make the user_sp aligned to 8B for k210 platform
         user_sp -= user_sp % core::mem::size_of::<usize>();
 
         let mut inner = self.inner_exclusive_access();
         inner.memory_set = memory_set;
         inner.trap_cx_ppn = trap_cx_ppn;
+        inner.base_size = image_end;
+        inner.program_brk = image_end;
 
         // set the new trap context
         let mut trap_cx = TrapContext::app_init_context(
@@ -175,12 +217,23 @@ impl TaskControlBlock {
         trap_cx.x[10] = args.len() as usize;
         trap_cx.x[11] = argv_base;
         *inner.get_trap_cx() = trap_cx;
+        Ok(())
     }
 
-    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
-        let mut parent_inner = self.inner_exclusive_access();
-
-        let child_memory_set = MemorySet::from_existed_user(&parent_inner.memory_set);
+    /// Fork a child, nothing is left behind if out of memory.
+    pub fn fork(self: &Arc<Self>) -> Result<Arc<Self>, OutOfMemory> {
+        // only the snapshot is taken with the parent borrowed, the copy can be
+        // preempted; the pages of the parent are pinned not to be swapped out
+        // meanwhile, and the parent is busy forking, not changing them
+        let (token, snapshot) = {
+            let inner = self.inner_exclusive_access();
+            let token = inner.memory_set.token();
+            pin_user_pages(token);
+            (token, inner.memory_set.snapshot())
+        };
+        let child_memory_set = MemorySet::from_snapshot(snapshot);
+        unpin_user_pages(token);
+        let child_memory_set = child_memory_set?;
         // content of child trap context also copied from parent in from_existed_user
         let child_trap_cx_ppn = child_memory_set
             .translate(VirtAddr::from(TRAP_CONTEXT).into())
@@ -188,8 +241,10 @@ impl TaskControlBlock {
             .ppn(); // init the page for trap context
 
         let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack = KernelStack::new(&pid_handle)?;
         let kernel_stack_top = kernel_stack.get_top();
+
+        let mut parent_inner = self.inner_exclusive_access();
         // copy fd table
         let mut new_fd_table = Vec::new();
         for fd in parent_inner.fd_table.iter() {
@@ -206,6 +261,7 @@ impl TaskControlBlock {
                     memory_set: child_memory_set,
                     trap_cx_ppn: child_trap_cx_ppn,
                     base_size: parent_inner.base_size,
+                    program_brk: parent_inner.program_brk,
                     parent: Some(Arc::downgrade(self)), // create a weak reference to parent
                     children: Vec::new(),
                     exit_code: 0,
@@ -217,6 +273,8 @@ impl TaskControlBlock {
                     killed: false,
                     frozen: false,
                     trap_ctx_backup: None,
+                    personality: parent_inner.personality,
+                    sleep_timer: None,
                 })
             },
         });
@@ -228,7 +286,7 @@ impl TaskControlBlock {
         let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
         trap_cx.kernel_sp = kernel_stack_top;
 
-        task_control_block
+        Ok(task_control_block)
     }
 
     pub fn getpid(&self) -> usize {
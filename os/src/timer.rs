@@ -1,17 +1,206 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::fmt;
+use lazy_static::lazy_static;
 use riscv::register::time;
-use crate::{config::CLOCK_FREQ, sbi::set_timer};
+
+use crate::{config::CLOCK_FREQ, drivers::RTC, sbi::set_timer, sync::UPSafeCell};
 
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
+const USEC_PER_SEC: usize = 1_000_000;
+const NSEC_PER_SEC: usize = 1_000_000_000;
+const SECS_PER_DAY: usize = 24 * 60 * 60;
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+/// struct timespec of clock_gettime
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSpec {
+    pub tv_sec: usize,
+    pub tv_nsec: usize,
+}
+
+/// struct timeval of gettimeofday
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TimeVal {
+    pub tv_sec: usize,
+    pub tv_usec: usize,
+}
+
+impl TimeSpec {
+    pub fn from_ns(ns: usize) -> Self {
+        Self {
+            tv_sec: ns / NSEC_PER_SEC,
+            tv_nsec: ns % NSEC_PER_SEC,
+        }
+    }
+}
+
+/// A UTC date and time, shown as `2000-01-31 23:59:59.999`
+pub struct DateTime {
+    year: usize,
+    month: usize,
+    day: usize,
+    secs: usize,
+    ms: usize,
+}
 
+impl DateTime {
+    /// from nanoseconds since the unix epoch
+    pub fn from_ns(ns: usize) -> Self {
+        let secs = ns / NSEC_PER_SEC;
+        // days to the civil date, counting from 0000-03-01 so that
+        // the leap day is the last day of a year and of a 400-year era
+        let z = secs / SECS_PER_DAY + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        Self {
+            year: era * 400 + yoe + (month <= 2) as usize,
+            month,
+            day: doy - (153 * mp + 2) / 5 + 1,
+            secs: secs % SECS_PER_DAY,
+            ms: ns % NSEC_PER_SEC / (NSEC_PER_SEC / MSEC_PER_SEC),
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+            self.year,
+            self.month,
+            self.day,
+            self.secs / 3600,
+            self.secs / 60 % 60,
+            self.secs % 60,
+            self.ms
+        )
+    }
+}
+
+/// current time in ticks of the timer, CLOCK_FREQ per second
 pub fn get_time() -> usize {
     time::read()
 }
 
-pub fn get_time_ms() -> usize {
-    get_time() / (CLOCK_FREQ / MSEC_PER_SEC)
+pub fn get_time_us() -> usize {
+    ticks_to_us(get_time())
+}
+
+/// CLOCK_FREQ may not be a multiple of USEC_PER_SEC,
+/// so split into whole seconds to keep the precision without overflow
+pub fn us_to_ticks(us: usize) -> usize {
+    us / USEC_PER_SEC * CLOCK_FREQ + us % USEC_PER_SEC * CLOCK_FREQ / USEC_PER_SEC
+}
+
+pub fn ticks_to_us(ticks: usize) -> usize {
+    ticks / CLOCK_FREQ * USEC_PER_SEC + ticks % CLOCK_FREQ * USEC_PER_SEC / CLOCK_FREQ
+}
+
+pub fn ticks_to_ns(ticks: usize) -> usize {
+    ticks / CLOCK_FREQ * NSEC_PER_SEC + ticks % CLOCK_FREQ * NSEC_PER_SEC / CLOCK_FREQ
 }
 
+/// wall clock time from the RTC, in nanoseconds since the unix epoch
+pub fn get_realtime_ns() -> usize {
+    RTC.read_ns() as usize
+}
+
+/// Program the next tick,
+/// or the deadline of a kernel timer if it comes earlier.
 pub fn set_next_trigger() {
-    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
-}
\ No newline at end of file
+    let next_tick = get_time() + CLOCK_FREQ / TICKS_PER_SEC;
+    set_timer(next_deadline().map_or(next_tick, |deadline| deadline.min(next_tick)));
+}
+
+/// Stop the periodic tick while idle, in tickless mode,
+/// and only wake up for the earliest kernel timer.
+pub fn set_idle_trigger() {
+    set_timer(next_deadline().unwrap_or(usize::MAX));
+}
+
+/// Handle of a timer added by `add_timer`, used to cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId {
+    // ordered by deadline first, seq breaks ties in the order of adding
+    deadline: usize,
+    seq: usize,
+}
+
+type TimerCallback = Box<dyn FnOnce() + Send>;
+
+/// Pending kernel timers, the earliest deadline first
+struct TimerQueue {
+    timers: BTreeMap<TimerId, TimerCallback>,
+    next_seq: usize,
+}
+
+lazy_static! {
+    static ref TIMER_QUEUE: UPSafeCell<TimerQueue> = unsafe {
+        UPSafeCell::new(TimerQueue {
+            timers: BTreeMap::new(),
+            next_seq: 0,
+        })
+    };
+}
+
+/// Run `callback` once the time reaches `deadline`, in ticks of `get_time`.
+/// Callbacks run from the timer interrupt, so they must not block,
+/// waking up a task with `wakeup_task` is the common one.
+pub fn add_timer<F>(deadline: usize, callback: F) -> TimerId
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut queue = TIMER_QUEUE.exclusive_access();
+    let id = TimerId {
+        deadline,
+        seq: queue.next_seq,
+    };
+    queue.next_seq += 1;
+    queue.timers.insert(id, Box::new(callback));
+    id
+}
+
+/// Cancel a timer, return false if it has fired or been cancelled already.
+pub fn cancel_timer(id: TimerId) -> bool {
+    TIMER_QUEUE.exclusive_access().timers.remove(&id).is_some()
+}
+
+/// Run the callbacks of expired timers, called on every timer interrupt.
+pub fn check_timer() {
+    let now = get_time();
+    let expired: Vec<TimerCallback> = {
+        let mut queue = TIMER_QUEUE.exclusive_access();
+        let mut expired = Vec::new();
+        while let Some(entry) = queue.timers.first_entry() {
+            if entry.key().deadline > now {
+                break;
+            }
+            expired.push(entry.remove());
+        }
+        expired
+    };
+    // the queue is released, so callbacks can add timers themselves
+    for callback in expired {
+        callback();
+    }
+}
+
+/// deadline of the earliest timer
+fn next_deadline() -> Option<usize> {
+    TIMER_QUEUE
+        .exclusive_access()
+        .timers
+        .keys()
+        .next()
+        .map(|id| id.deadline)
+}
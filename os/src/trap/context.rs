@@ -39,3 +39,13 @@ impl TrapContext {
         cx
     }
 }
+
+/// Context saved on the kernel stack by __alltraps_k
+/// when a trap is taken in kernel mode
+#[repr(C)]
+#[derive(Debug)]
+pub struct KernelTrapContext {
+    pub x: [usize; 32],
+    pub sstatus: Sstatus,
+    pub sepc: usize,
+}
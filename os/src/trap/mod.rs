@@ -1,4 +1,5 @@
 pub use context::TrapContext;
+use context::KernelTrapContext;
 use core::arch::{asm, global_asm};
 use riscv::register::{
     scause::{self, Exception, Interrupt, Trap},
@@ -7,19 +8,32 @@ use riscv::register::{
 };
 
 use crate::{
-    config::{TRAMPOLINE, TRAP_CONTEXT},
+    config::{KERNEL_STACK_SIZE, MEMORY_END, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT},
+    mm::{
+        flush_write_backs, pin_user_pages, unpin_user_pages, FaultError, MapPermission, VirtAddr,
+    },
     println,
+    sync::{intr_nested_level, intr_off, intr_on},
     syscall::syscall,
     task::{
-        check_signals_error_of_current, current_add_signal, current_trap_cx, current_user_token,
-        exit_current_and_run_next, handle_signals, suspend_current_and_run_next, SignalFlags,
+        check_signals_error_of_current, current_add_signal, current_task, current_trap_cx,
+        current_user_token, exit_current_and_run_next, fault_in_current, handle_signals, kernel_stack_guard_owner,
+        kernel_stack_owner, kernel_stack_position, out_of_memory, preempt_current,
+        preempt_enabled, suspend_current_and_run_next, SignalFlags,
     },
-    timer::set_next_trigger,
+    timer::{check_timer, set_next_trigger},
 };
 
 pub mod context;
 
-global_asm!(include_str!("trap.S"));
+// __alltraps_k checks for kernel stack overflows with the layout of kernel_stack_position
+global_asm!(
+    include_str!("trap.S"),
+    kstack_size = const KERNEL_STACK_SIZE,
+    kstack_stride = const KERNEL_STACK_SIZE + PAGE_SIZE,
+    memory_end = const MEMORY_END,
+    trampoline = const TRAMPOLINE,
+);
 
 pub fn init() {
     set_kernel_trap_entry();
@@ -27,8 +41,11 @@ pub fn init() {
 }
 
 fn set_kernel_trap_entry() {
+    extern "C" {
+        fn __alltraps_k();
+    }
     unsafe {
-        stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+        stvec::write(__alltraps_k as usize, TrapMode::Direct);
     }
 }
 
@@ -45,29 +62,62 @@ pub fn enable_timer_interrupt() {
     }
 }
 
+/// the permission a page fault asks for
+fn page_fault_access(cause: Trap) -> MapPermission {
+    match cause {
+        Trap::Exception(Exception::StorePageFault) => MapPermission::W,
+        Trap::Exception(Exception::InstructionPageFault) => MapPermission::X,
+        _ => MapPermission::R,
+    }
+}
+
 #[no_mangle]
 /// handle an interrupt, exception, or system call from user space
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
     let scause = scause::read();
     let stval = stval::read();
+    // traps from now on go to __alltraps_k, so the kernel can be interrupted
+    intr_on();
     match scause.cause() {
         Trap::Exception(Exception::UserEnvCall) => {
             let mut cx = current_trap_cx();
             cx.sepc += 4;
-            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]) as usize;
+            // syscalls access user pages by physical address, keep them in memory
+            let token = current_user_token();
+            pin_user_pages(token);
+            let result = syscall(
+                cx.x[17],
+                [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]],
+            ) as usize;
+            unpin_user_pages(token);
 
             // for sys_exec, the previous trap context is invalid
             // because the memory set has been changed
             cx = current_trap_cx();
             cx.x[10] = result;
         }
-        Trap::Exception(Exception::StoreFault)
-        | Trap::Exception(Exception::StorePageFault)
-        | Trap::Exception(Exception::InstructionFault)
+        Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionPageFault)
-        | Trap::Exception(Exception::LoadFault)
         | Trap::Exception(Exception::LoadPageFault) => {
+            match fault_in_current(
+                current_user_token(),
+                VirtAddr::from(stval).floor(),
+                page_fault_access(scause.cause()),
+            ) {
+                // first touch of a lazy page, run the instruction again
+                Ok(()) => {}
+                // again after some task is killed, if it is this one
+                // the signal is handled before
+                Err(FaultError::OutOfMemory) => {
+                    out_of_memory();
+                }
+                Err(FaultError::Invalid) => current_add_signal(SignalFlags::SIGSEGV),
+            }
+        }
+        Trap::Exception(Exception::StoreFault)
+        | Trap::Exception(Exception::InstructionFault)
+        | Trap::Exception(Exception::LoadFault) => {
             // println!("[kernel] PageFault in application, bad addr = {:#x}, bad instruction = {:#x}, kernel killed it.", stval, current_trap_cx().sepc);
             // exit_current_and_run_next(-2);
             current_add_signal(SignalFlags::SIGSEGV);
@@ -78,6 +128,7 @@ pub fn trap_handler() -> ! {
             current_add_signal(SignalFlags::SIGILL);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            check_timer();
             set_next_trigger();
             suspend_current_and_run_next();
         }
@@ -90,6 +141,8 @@ pub fn trap_handler() -> ! {
         }
     }
     handle_signals();
+    // munmap, msync, exec and the like leave the dirty file pages queued
+    flush_write_backs();
 
     if let Some((errno, msg)) = check_signals_error_of_current() {
         println!(
@@ -109,6 +162,9 @@ pub fn trap_handler() -> ! {
 /// set the reg a0 = trap_cx_ptr, reg a1 = phy addr of usr page table,
 /// finally, jump to new addr of __restore asm function
 pub fn trap_return() -> ! {
+    // no kernel traps until we are back in user mode,
+    // __restore only expects to be entered from trap_return
+    intr_off();
     set_user_trap_entry();
     let trap_cx_ptr = TRAP_CONTEXT;
     let user_satp = current_user_token();
@@ -133,8 +189,79 @@ pub fn trap_return() -> ! {
 }
 
 #[no_mangle]
-/// Unimplement: traps/interrupts/exceptions from kernel mode
-/// Todo: Chapter 9: I/O device
-pub fn trap_from_kernel() -> ! {
-    panic!("a trap from kernel!");
+/// handle an interrupt or exception from kernel mode,
+/// interrupts are disabled until we return to __restore_k
+pub extern "C" fn kernel_trap_handler(cx: &mut KernelTrapContext) {
+    let scause = scause::read();
+    let stval = stval::read();
+    let guard_owner = kernel_stack_guard_owner(stval);
+    match scause.cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            check_timer();
+            set_next_trigger();
+            preempt_current();
+        }
+        // a frame larger than the guard page skipped over it,
+        // the access below sp still hits the guard page
+        Trap::Exception(Exception::StorePageFault) | Trap::Exception(Exception::LoadPageFault)
+            if guard_owner.is_some() =>
+        {
+            handle_kernel_stack_overflow(guard_owner.unwrap(), cx);
+        }
+        _ => {
+            panic!(
+                "Unsupported trap from kernel {:?}, stval = {:#x}, sepc = {:#x}!",
+                scause.cause(),
+                stval,
+                cx.sepc
+            );
+        }
+    }
+}
+
+#[no_mangle]
+/// called by __alltraps_k on the overflow stack
+/// when a trap came with sp in, or right above, a kernel stack guard page
+pub extern "C" fn kernel_stack_overflow(cx: &mut KernelTrapContext) -> ! {
+    handle_kernel_stack_overflow(kernel_stack_owner(cx.x[2]), cx)
+}
+
+fn handle_kernel_stack_overflow(pid: usize, cx: &KernelTrapContext) -> ! {
+    println!(
+        "[kernel] Kernel stack of task {} overflowed, sepc = {:#x}, sp = {:#x}, stval = {:#x}",
+        pid,
+        cx.sepc,
+        cx.x[2],
+        stval::read()
+    );
+    print_backtrace(cx.sepc, cx.x[8], kernel_stack_position(pid));
+
+    // the task can only be killed when it borrows no UPSafeCell and holds no
+    // preempt guard, otherwise others may wait forever for what it holds
+    if intr_nested_level() == 0
+        && current_task().map(|task| task.getpid()) == Some(pid)
+        && preempt_enabled()
+    {
+        println!("[kernel] Task {} killed, its kernel stack overflowed", pid);
+        exit_current_and_run_next(-11);
+    }
+    panic!("kernel stack overflow in task {}", pid);
+}
+
+/// Print return addresses along the frame pointer chain,
+/// each frame stores ra at fp - 8 and the previous fp at fp - 16.
+/// Only frames inside the stack `(bottom, top)` are followed, as it may be corrupted.
+fn print_backtrace(pc: usize, mut fp: usize, (bottom, top): (usize, usize)) {
+    const MAX_DEPTH: usize = 32;
+    println!("---START BACKTRACE---");
+    println!("#0 {:#x}", pc);
+    for depth in 1..MAX_DEPTH {
+        if fp % 8 != 0 || fp < bottom + 16 || fp > top {
+            break;
+        }
+        let ra = unsafe { *((fp - 8) as *const usize) };
+        fp = unsafe { *((fp - 16) as *const usize) };
+        println!("#{} {:#x}", depth, ra);
+    }
+    println!("---END BACKTRACE---");
 }
@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{exec, exit, fork, mmap, personality, waitpid, MapFlags, Personality, ProtFlags};
+
+const PAGE_SIZE: usize = 4096;
+const RUNS: usize = 4;
+
+/// the page of a local variable or of a new mapping, as the exit code
+fn report(what: &str) -> i32 {
+    let addr = match what {
+        "stack" => {
+            let local = 0u8;
+            &local as *const u8 as usize
+        }
+        "mmap" => {
+            let prot = ProtFlags::READ | ProtFlags::WRITE;
+            let addr = mmap(0, PAGE_SIZE, prot, MapFlags::ANONYMOUS | MapFlags::PRIVATE);
+            assert!(addr > 0);
+            addr as usize
+        }
+        _ => panic!("unknown layout {}", what),
+    };
+    (addr / PAGE_SIZE) as i32
+}
+
+/// run this program again to report `what`
+fn layout_of(what: &str) -> i32 {
+    let pid = fork();
+    if pid == 0 {
+        let args = [
+            "aslr_test\0".as_ptr(),
+            what.as_ptr(),
+            core::ptr::null::<u8>(),
+        ];
+        exec("aslr_test\0", &args);
+        panic!("exec failed");
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    exit_code
+}
+
+#[no_mangle]
+pub fn main(argc: usize, argv: &[&str]) -> i32 {
+    if argc > 1 {
+        exit(report(argv[1]));
+    }
+    for what in ["stack\0", "mmap\0"] {
+        let pages = [0; RUNS].map(|_| layout_of(what));
+        println!("{} pages: {:?}", what, pages);
+        assert!(pages.iter().any(|&page| page != pages[0]));
+    }
+
+    // the same every time with randomization turned off
+    assert_eq!(personality(Personality::ADDR_NO_RANDOMIZE), 0);
+    for what in ["stack\0", "mmap\0"] {
+        assert_eq!(layout_of(what), layout_of(what));
+    }
+    assert_eq!(
+        personality(Personality::empty()),
+        Personality::ADDR_NO_RANDOMIZE.bits() as isize
+    );
+    println!("aslr_test passed!");
+    0
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::vec::Vec;
+use user_lib::{brk, errno::Errno, mmap, mprotect, munmap, sbrk, MapFlags, ProtFlags};
+
+const PAGE_SIZE: usize = 4096;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // nothing is allocated with sbrk yet, the heap is empty
+    let bottom = brk(0) as usize;
+    assert_eq!(bottom % PAGE_SIZE, 0);
+    let rw = ProtFlags::READ | ProtFlags::WRITE;
+
+    // a mapping where the emptied heap was is not taken for the heap
+    assert_eq!(sbrk(PAGE_SIZE as isize), bottom as isize);
+    assert_eq!(brk(bottom), bottom as isize);
+    let fixed = MapFlags::PRIVATE | MapFlags::ANONYMOUS | MapFlags::FIXED;
+    assert_eq!(mmap(bottom, PAGE_SIZE, rw, fixed), bottom as isize);
+    unsafe { (bottom as *mut u8).write_volatile(0x5a) };
+    assert_eq!(brk(bottom + 2 * PAGE_SIZE), bottom as isize);
+    assert_eq!(brk(bottom + PAGE_SIZE / 2), bottom as isize);
+    assert_eq!(unsafe { (bottom as *const u8).read_volatile() }, 0x5a);
+    assert_eq!(munmap(bottom, PAGE_SIZE), 0);
+
+    // the heap is not split, only changed as a whole
+    assert_eq!(sbrk(2 * PAGE_SIZE as isize), bottom as isize);
+    assert_eq!(mprotect(bottom + PAGE_SIZE, PAGE_SIZE, ProtFlags::READ), Errno::EINVAL.as_ret());
+    assert_eq!(mprotect(bottom, PAGE_SIZE, ProtFlags::READ), Errno::EINVAL.as_ret());
+    assert_eq!(munmap(bottom + PAGE_SIZE, PAGE_SIZE), Errno::EINVAL.as_ret());
+    assert_eq!(mprotect(bottom, 2 * PAGE_SIZE, rw), 0);
+    assert_eq!(sbrk(PAGE_SIZE as isize), (bottom + 2 * PAGE_SIZE) as isize);
+    let heap = unsafe { core::slice::from_raw_parts_mut(bottom as *mut u8, 3 * PAGE_SIZE) };
+    heap.fill(0x3c);
+    assert!(heap.iter().all(|&b| b == 0x3c));
+    // unmapping all of it empties the heap, it grows again from the bottom
+    assert_eq!(munmap(bottom, 3 * PAGE_SIZE), 0);
+    assert_eq!(brk(bottom + PAGE_SIZE), (bottom + PAGE_SIZE) as isize);
+    let page = unsafe { core::slice::from_raw_parts(bottom as *const u8, PAGE_SIZE) };
+    assert!(page.iter().all(|&b| b == 0));
+    assert_eq!(brk(bottom), bottom as isize);
+
+    // far more than the 16 KiB the heap starts with
+    let mut vecs: Vec<Vec<usize>> = Vec::new();
+    for i in 0..64 {
+        vecs.push((0..2048).map(|j| i * j).collect());
+    }
+    for (i, v) in vecs.iter().enumerate() {
+        assert!(v.iter().enumerate().all(|(j, &x)| x == i * j));
+    }
+    drop(vecs);
+
+    // sbrk returns the old break, the new memory is usable
+    let old_brk = sbrk(0);
+    assert!(old_brk > 0);
+    assert_eq!(sbrk(2 * PAGE_SIZE as isize), old_brk);
+    assert_eq!(sbrk(0), old_brk + 2 * PAGE_SIZE as isize);
+    let mem = unsafe { core::slice::from_raw_parts_mut(old_brk as *mut u8, 2 * PAGE_SIZE) };
+    mem.fill(0xa5);
+    assert!(mem.iter().all(|&b| b == 0xa5));
+
+    // shrink back, then the break can not go below the heap or out of user space
+    assert_eq!(brk(old_brk as usize), old_brk);
+    assert_eq!(brk(0), old_brk);
+    assert_eq!(sbrk(isize::MAX), Errno::ENOMEM.as_ret());
+    assert_eq!(brk(0), old_brk);
+
+    println!("brk_test passed!");
+    0
+}
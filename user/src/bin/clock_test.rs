@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    clock_gettime, errno::Errno, gettimeofday, sleep, TimeSpec, TimeVal, CLOCK_MONOTONIC,
+    CLOCK_REALTIME,
+};
+
+// 2020-01-01T00:00:00Z
+const EPOCH_2020: usize = 1577836800;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut realtime = TimeSpec::default();
+    assert_eq!(clock_gettime(CLOCK_REALTIME, &mut realtime), 0);
+    assert!(realtime.tv_sec > EPOCH_2020);
+    assert!(realtime.tv_nsec < 1_000_000_000);
+
+    let mut tv = TimeVal::default();
+    assert_eq!(gettimeofday(&mut tv), 0);
+    assert!(tv.tv_usec < 1_000_000);
+    assert!(tv.tv_sec >= realtime.tv_sec && tv.tv_sec - realtime.tv_sec <= 1);
+
+    let mut start = TimeSpec::default();
+    let mut end = TimeSpec::default();
+    assert_eq!(clock_gettime(CLOCK_MONOTONIC, &mut start), 0);
+    sleep(100);
+    assert_eq!(clock_gettime(CLOCK_MONOTONIC, &mut end), 0);
+    let elapsed_ns =
+        (end.tv_sec - start.tv_sec) * 1_000_000_000 + end.tv_nsec - start.tv_nsec;
+    assert!(elapsed_ns >= 100_000_000);
+
+    assert_eq!(clock_gettime(42, &mut start), Errno::EINVAL.as_ret());
+
+    println!(
+        "realtime = {}.{:09}s, slept {}ns",
+        realtime.tv_sec, realtime.tv_nsec, elapsed_ns
+    );
+    println!("clock_test passed!");
+    0
+}
@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::errno::{check, Errno};
+use user_lib::{
+    clock_gettime, close, dup, exec, kill, mmap, open, pipe, read, sigaction, wait, write,
+    MapFlags, OpenFlags, ProtFlags, SignalAction, TimeSpec,
+};
+
+/// below any user mapping
+const UNMAPPED: usize = 0x10;
+/// the TrapContext page, mapped but not for user space
+const TRAP_CONTEXT: usize = usize::MAX - 2 * 4096 + 1;
+/// PATH_MAX, the most bytes of a path or an argument with its `\0`
+const PATH_MAX: usize = 4096;
+
+fn user_bytes(addr: usize, len: usize) -> &'static mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    assert_eq!(check(close(99)), Err(Errno::EBADF));
+    assert_eq!(check(dup(99)), Err(Errno::EBADF));
+    assert_eq!(check(read(99, &mut [0u8; 4])), Err(Errno::EBADF));
+    assert_eq!(
+        check(open("errno_test_no_such_file\0", OpenFlags::RDONLY)),
+        Err(Errno::ENOENT)
+    );
+    assert_eq!(check(wait(&mut 0i32)), Err(Errno::ECHILD));
+    assert_eq!(check(kill(99999, 10)), Err(Errno::ESRCH));
+    let new = SignalAction::default();
+    let mut old = SignalAction::default();
+    assert_eq!(
+        check(sigaction(50, Some(&new), Some(&mut old))),
+        Err(Errno::EINVAL)
+    );
+    // either action may be null, to only query or only set
+    assert_eq!(sigaction(10, None, None), 0);
+    let ignored = SignalAction {
+        handler: 1,
+        ..SignalAction::default()
+    };
+    assert_eq!(sigaction(10, Some(&ignored), None), 0);
+    assert_eq!(sigaction(10, None, Some(&mut old)), 0);
+    assert_eq!(old.handler, 1);
+    assert_eq!(sigaction(10, Some(&new), None), 0);
+
+    // names and arguments are bounded
+    let mut long = [b'a'; PATH_MAX + 1];
+    long[PATH_MAX] = 0;
+    let path = unsafe { core::str::from_utf8_unchecked(&long) };
+    assert_eq!(
+        check(open(path, OpenFlags::RDONLY)),
+        Err(Errno::ENAMETOOLONG)
+    );
+    let args = [long.as_ptr(), core::ptr::null()];
+    assert_eq!(check(exec("errno_test\0", &args)), Err(Errno::E2BIG));
+    long[PATH_MAX - 1] = 0;
+    let path = unsafe { core::str::from_utf8_unchecked(&long) };
+    assert_eq!(check(open(path, OpenFlags::RDONLY)), Err(Errno::ENOENT));
+
+    // bad user pointers are refused rather than followed
+    let mut fds = [0usize; 2];
+    assert_eq!(pipe(&mut fds), 0);
+    assert_eq!(write(fds[1], b"data"), 4);
+    assert_eq!(
+        check(read(fds[0], user_bytes(UNMAPPED, 4))),
+        Err(Errno::EFAULT)
+    );
+    assert_eq!(
+        check(write(fds[1], user_bytes(UNMAPPED, 4))),
+        Err(Errno::EFAULT)
+    );
+    assert_eq!(
+        check(write(fds[1], user_bytes(TRAP_CONTEXT, 4))),
+        Err(Errno::EFAULT)
+    );
+    let path = unsafe { core::str::from_utf8_unchecked(user_bytes(UNMAPPED, 1)) };
+    assert_eq!(check(open(path, OpenFlags::RDONLY)), Err(Errno::EFAULT));
+    let bad = unsafe { &*(UNMAPPED as *const SignalAction) };
+    assert_eq!(check(sigaction(10, Some(bad), None)), Err(Errno::EFAULT));
+    let ts = unsafe { &mut *(UNMAPPED as *mut TimeSpec) };
+    assert_eq!(check(clock_gettime(0, ts)), Err(Errno::EFAULT));
+    // nor written through when the user can not write
+    let ro = mmap(
+        0,
+        4096,
+        ProtFlags::READ,
+        MapFlags::ANONYMOUS | MapFlags::PRIVATE,
+    );
+    assert!(ro > 0);
+    assert_eq!(
+        check(read(fds[0], user_bytes(ro as usize, 4))),
+        Err(Errno::EFAULT)
+    );
+    let old = unsafe { &mut *(ro as *mut SignalAction) };
+    assert_eq!(
+        check(sigaction(10, Some(&new), Some(old))),
+        Err(Errno::EFAULT)
+    );
+    let old = unsafe { &mut *(ro as *mut SignalAction) };
+    assert_eq!(check(sigaction(10, None, Some(old))), Err(Errno::EFAULT));
+    // the data is still there
+    let mut buf = [0u8; 4];
+    assert_eq!(read(fds[0], &mut buf), 4);
+    assert_eq!(&buf, b"data");
+    close(fds[0]);
+    close(fds[1]);
+    println!("errno_test passed!");
+    0
+}
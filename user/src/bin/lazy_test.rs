@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{close, exit, fork, mmap, munmap, pipe, read, waitpid, write, MapFlags, ProtFlags};
+
+const PAGE_SIZE: usize = 4096;
+/// far more than the physical memory, only the touched pages get frames
+const LEN: usize = 1 << 30;
+
+fn page(addr: usize) -> &'static mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, PAGE_SIZE) }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let prot = ProtFlags::READ | ProtFlags::WRITE;
+    let flags = MapFlags::ANONYMOUS | MapFlags::PRIVATE;
+
+    let addr = mmap(0, LEN, prot, flags);
+    assert!(addr > 0);
+    let addr = addr as usize;
+    let last = addr + LEN - PAGE_SIZE;
+    assert!(page(addr).iter().all(|&b| b == 0));
+    page(addr).fill(0x11);
+    page(last).fill(0x22);
+
+    // the kernel faults in the pages it writes for us,
+    // the page goes through a pipe far smaller, so a child writes it
+    let mut fds = [0usize; 2];
+    assert_eq!(pipe(&mut fds), 0);
+    let pid = fork();
+    if pid == 0 {
+        close(fds[0]);
+        assert_eq!(write(fds[1], page(addr)), PAGE_SIZE as isize);
+        exit(0);
+    }
+    close(fds[1]);
+    let middle = addr + LEN / 2;
+    assert_eq!(read(fds[0], page(middle)), PAGE_SIZE as isize);
+    assert!(page(middle).iter().all(|&b| b == 0x11));
+    close(fds[0]);
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+
+    // the child gets the touched pages, the others are still fresh
+    let pid = fork();
+    if pid == 0 {
+        assert!(page(last).iter().all(|&b| b == 0x22));
+        assert!(page(last - PAGE_SIZE).iter().all(|&b| b == 0));
+        page(addr).fill(0x33);
+        exit(0);
+    }
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    assert!(page(addr).iter().all(|&b| b == 0x11));
+
+    // a read-only lazy page can be read but not written
+    let ro = mmap(0, PAGE_SIZE, ProtFlags::READ, flags);
+    assert!(ro > 0);
+    let ro = ro as usize;
+    assert!(page(ro).iter().all(|&b| b == 0));
+    let pid = fork();
+    if pid == 0 {
+        page(ro)[0] = 1;
+        exit(0);
+    }
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, -11);
+
+    assert_eq!(munmap(ro, PAGE_SIZE), 0);
+    assert_eq!(munmap(addr, LEN), 0);
+    println!("lazy_test passed!");
+    0
+}
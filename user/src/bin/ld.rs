@@ -0,0 +1,353 @@
+//! The dynamic loader, named in PT_INTERP of a dynamically linked program.
+//! The kernel maps the program and this loader, then starts here with the
+//! auxiliary vector after argv. The shared objects in DT_NEEDED are read
+//! into anonymous mappings, everything is relocated, and control goes to
+//! the program's entry with the same argc and argv.
+//! Symbols are looked up through DT_HASH, so link with --hash-style=sysv or both.
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use core::arch::asm;
+use user_lib::{
+    auxv, close, exit, getauxval, mmap, mprotect, open, read, MapFlags, OpenFlags, ProtFlags,
+    AT_ENTRY, AT_PHDR, AT_PHNUM,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_PHDR: u32 = 6;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_JMPREL: u64 = 23;
+
+const R_RISCV_NONE: u64 = 0;
+const R_RISCV_64: u64 = 2;
+const R_RISCV_RELATIVE: u64 = 3;
+const R_RISCV_JUMP_SLOT: u64 = 5;
+
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    type_: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+struct ProgramHeader {
+    type_: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+/// A loaded object, the program or a shared object
+struct Object {
+    name: String,
+    /// added to every address in the object
+    bias: usize,
+    phdrs: &'static [ProgramHeader],
+    /// (tag, value) of PT_DYNAMIC
+    dynamic: Vec<(u64, u64)>,
+}
+
+fn fail(msg: &str) -> ! {
+    println!("ld: {}", msg);
+    exit(-1)
+}
+
+impl Object {
+    fn dynamic(&self, tag: u64) -> Option<usize> {
+        self.dynamic
+            .iter()
+            .find(|&&(t, _)| t == tag)
+            .map(|&(_, value)| value as usize)
+    }
+
+    /// the address of `va` in the object
+    fn at<T>(&self, va: usize) -> *mut T {
+        (self.bias + va) as *mut T
+    }
+
+    fn string(&self, offset: usize) -> &'static str {
+        let strtab = self
+            .dynamic(DT_STRTAB)
+            .unwrap_or_else(|| fail("no DT_STRTAB"));
+        let start = self.at::<u8>(strtab + offset);
+        let len = (0..).find(|&i| unsafe { *start.add(i) } == 0).unwrap();
+        unsafe { core::str::from_utf8_unchecked(core::slice::from_raw_parts(start, len)) }
+    }
+
+    fn symbol(&self, index: usize) -> &'static Symbol {
+        let symtab = self
+            .dynamic(DT_SYMTAB)
+            .unwrap_or_else(|| fail("no DT_SYMTAB"));
+        unsafe { &*self.at::<Symbol>(symtab).add(index) }
+    }
+
+    /// where `name` is defined in the object
+    fn lookup(&self, name: &str) -> Option<usize> {
+        let hash = self.dynamic(DT_HASH)?;
+        // nchain is the number of symbols
+        let count = unsafe { *self.at::<u32>(hash).add(1) } as usize;
+        (1..count)
+            .map(|i| self.symbol(i))
+            .find(|sym| sym.shndx != 0 && self.string(sym.name as usize) == name)
+            .map(|sym| self.bias + sym.value as usize)
+    }
+
+    fn read_dynamic(&mut self) {
+        let dynamic = match self.phdrs.iter().find(|ph| ph.type_ == PT_DYNAMIC) {
+            Some(ph) => self.at::<[u64; 2]>(ph.vaddr as usize),
+            None => return,
+        };
+        for i in 0.. {
+            let [tag, value] = unsafe { *dynamic.add(i) };
+            if tag == DT_NULL {
+                break;
+            }
+            self.dynamic.push((tag, value));
+        }
+    }
+
+    fn needed(&self) -> Vec<&'static str> {
+        self.dynamic
+            .iter()
+            .filter(|&&(tag, _)| tag == DT_NEEDED)
+            .map(|&(_, offset)| self.string(offset as usize))
+            .collect()
+    }
+}
+
+/// Read the whole file at `path`.
+fn read_file(path: &str) -> Vec<u8> {
+    let mut cpath = String::from(path);
+    cpath.push('\0');
+    let fd = open(cpath.as_str(), OpenFlags::RDONLY);
+    if fd < 0 {
+        fail("can not open a needed object");
+    }
+    let mut data = Vec::new();
+    let mut buf = [0u8; PAGE_SIZE];
+    loop {
+        let len = read(fd as usize, &mut buf);
+        if len <= 0 {
+            break;
+        }
+        data.extend_from_slice(&buf[..len as usize]);
+    }
+    close(fd as usize);
+    data
+}
+
+/// Load the shared object `name` wherever mmap puts it.
+fn load(name: &str) -> Object {
+    let data = read_file(name);
+    if data.len() < core::mem::size_of::<ElfHeader>() || data[..4] != *b"\x7fELF" {
+        fail("a needed object is not elf");
+    }
+    let header = unsafe { &*(data.as_ptr() as *const ElfHeader) };
+    let phdrs_in_file = unsafe {
+        core::slice::from_raw_parts(
+            data.as_ptr().add(header.phoff as usize) as *const ProgramHeader,
+            header.phnum as usize,
+        )
+    };
+    let loads = || phdrs_in_file.iter().filter(|ph| ph.type_ == PT_LOAD);
+    let start = loads().map(|ph| ph.vaddr as usize).min().unwrap() & !(PAGE_SIZE - 1);
+    let end = loads()
+        .map(|ph| (ph.vaddr + ph.memsz) as usize)
+        .max()
+        .unwrap();
+    let base = mmap(
+        0,
+        end - start,
+        ProtFlags::READ | ProtFlags::WRITE,
+        MapFlags::ANONYMOUS | MapFlags::PRIVATE,
+    );
+    if base < 0 {
+        fail("no memory for a needed object");
+    }
+    let bias = base as usize - start;
+    for ph in loads() {
+        let file = &data[ph.offset as usize..(ph.offset + ph.filesz) as usize];
+        let dst = (bias + ph.vaddr as usize) as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(file.as_ptr(), dst, file.len()) };
+    }
+    // the program headers are kept in the mapping if they are loaded at all
+    let phdr_va = loads()
+        .find(|ph| ph.offset <= header.phoff && header.phoff < ph.offset + ph.filesz)
+        .map(|ph| ph.vaddr + header.phoff - ph.offset)
+        .unwrap_or_else(|| fail("program headers of a needed object are not loaded"));
+    let mut object = Object {
+        name: String::from(name),
+        bias,
+        phdrs: unsafe {
+            core::slice::from_raw_parts(
+                (bias + phdr_va as usize) as *const ProgramHeader,
+                header.phnum as usize,
+            )
+        },
+        dynamic: Vec::new(),
+    };
+    object.read_dynamic();
+    object
+}
+
+/// Apply the relocations of `objects[index]`, symbols are looked up in all of them in order.
+fn relocate(objects: &[Object], index: usize) {
+    let object = &objects[index];
+    let tables = [(DT_RELA, DT_RELASZ), (DT_JMPREL, DT_PLTRELSZ)];
+    for (table, size) in tables {
+        let (table, size) = match (object.dynamic(table), object.dynamic(size)) {
+            (Some(table), Some(size)) => (table, size),
+            _ => continue,
+        };
+        let relas = unsafe {
+            core::slice::from_raw_parts(
+                object.at::<Rela>(table),
+                size / core::mem::size_of::<Rela>(),
+            )
+        };
+        for rela in relas {
+            let value = match rela.info & 0xffff_ffff {
+                R_RISCV_NONE => continue,
+                R_RISCV_RELATIVE => object.bias.wrapping_add(rela.addend as usize),
+                R_RISCV_64 | R_RISCV_JUMP_SLOT => {
+                    let sym = object.symbol((rela.info >> 32) as usize);
+                    let name = object.string(sym.name as usize);
+                    let addr = objects
+                        .iter()
+                        .find_map(|object| object.lookup(name))
+                        .unwrap_or_else(|| {
+                            println!("ld: {} needs {}", object.name, name);
+                            fail("undefined symbol")
+                        });
+                    addr.wrapping_add(rela.addend as usize)
+                }
+                _ => fail("unsupported relocation"),
+            };
+            unsafe { *object.at::<usize>(rela.offset as usize) = value };
+        }
+    }
+}
+
+/// Give the loaded segments of a shared object their own permissions.
+fn protect(object: &Object) {
+    for ph in object.phdrs.iter().filter(|ph| ph.type_ == PT_LOAD) {
+        let start = (object.bias + ph.vaddr as usize) & !(PAGE_SIZE - 1);
+        let end = object.bias + (ph.vaddr + ph.memsz) as usize;
+        let mut prot = ProtFlags::empty();
+        if ph.flags & PF_R != 0 {
+            prot |= ProtFlags::READ;
+        }
+        if ph.flags & PF_W != 0 {
+            prot |= ProtFlags::WRITE;
+        }
+        if ph.flags & PF_X != 0 {
+            prot |= ProtFlags::EXEC;
+        }
+        mprotect(start, end - start, prot);
+    }
+}
+
+#[no_mangle]
+pub fn main(argc: usize, _argv: &[&str]) -> i32 {
+    let (phdr, phnum, entry) = match (getauxval(AT_PHDR), getauxval(AT_PHNUM), getauxval(AT_ENTRY))
+    {
+        (Some(phdr), Some(phnum), Some(entry)) => (phdr, phnum, entry),
+        _ => fail("not started for a program"),
+    };
+    let phdrs = unsafe { core::slice::from_raw_parts(phdr as *const ProgramHeader, phnum) };
+    // the kernel loaded the program, PT_PHDR tells where
+    let bias = match phdrs.iter().find(|ph| ph.type_ == PT_PHDR) {
+        Some(ph) => phdr - ph.vaddr as usize,
+        None => fail("the program has no PT_PHDR"),
+    };
+    let mut program = Object {
+        name: String::from("program"),
+        bias,
+        phdrs,
+        dynamic: Vec::new(),
+    };
+    program.read_dynamic();
+
+    // the program comes first in the lookup order, then the objects breadth first
+    let mut objects = Vec::from([program]);
+    let mut i = 0;
+    while i < objects.len() {
+        for name in objects[i].needed() {
+            if !objects.iter().any(|object| object.name == name) {
+                objects.push(load(name));
+            }
+        }
+        i += 1;
+    }
+    for i in (0..objects.len()).rev() {
+        relocate(&objects, i);
+    }
+    for object in objects.iter().skip(1) {
+        protect(object);
+    }
+
+    // start the program with the argv and auxv it got from the kernel
+    let argv = unsafe { auxv().sub(argc + 1) };
+    unsafe {
+        asm!(
+            "fence.i",
+            "jr {entry}",
+            entry = in(reg) entry,
+            in("a0") argc,
+            in("a1") argv,
+            options(noreturn)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    close, errno::Errno, exit, fork, mmap_file, msync, munmap, open, read, waitpid, write,
+    MapFlags, MsyncFlags, OpenFlags, ProtFlags,
+};
+
+const PAGE_SIZE: usize = 4096;
+/// two pages and a bit, the last page is partly past the end of the file
+const FILE_LEN: usize = 2 * PAGE_SIZE + 100;
+const MAP_LEN: usize = 3 * PAGE_SIZE;
+const FILE: &str = "mmap_file\0";
+
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+fn bytes(addr: usize, len: usize) -> &'static mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) }
+}
+
+/// read the whole file through a new fd
+fn read_file(buf: &mut [u8]) -> usize {
+    let fd = open(FILE, OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let mut total = 0;
+    loop {
+        let len = read(fd as usize, &mut buf[total..]);
+        assert!(len >= 0);
+        if len == 0 {
+            break;
+        }
+        total += len as usize;
+    }
+    close(fd as usize);
+    total
+}
+
+fn map(fd: usize, prot: ProtFlags, flags: MapFlags) -> usize {
+    let addr = mmap_file(0, MAP_LEN, prot, flags, fd, 0);
+    assert!(addr > 0);
+    addr as usize
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut data = [0u8; FILE_LEN];
+    for (i, b) in data.iter_mut().enumerate() {
+        *b = pattern(i);
+    }
+    let fd = open(FILE, OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, &data), FILE_LEN as isize);
+    close(fd as usize);
+
+    let fd = open(FILE, OpenFlags::RDWR);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    let prot = ProtFlags::READ | ProtFlags::WRITE;
+    let shared = map(fd, prot, MapFlags::SHARED);
+    let private = map(fd, prot, MapFlags::PRIVATE);
+
+    // the pages come from the file, zero past its end
+    assert_eq!(&bytes(shared, FILE_LEN)[..], &data[..]);
+    assert!(bytes(shared + FILE_LEN, MAP_LEN - FILE_LEN).iter().all(|&b| b == 0));
+    assert_eq!(&bytes(private, FILE_LEN)[..], &data[..]);
+
+    // private writes stay private
+    bytes(private, 10).fill(0xaa);
+    bytes(private + 2 * PAGE_SIZE, 10).fill(0xaa);
+    assert_eq!(bytes(shared, 1)[0], pattern(0));
+
+    // shared writes reach the file on msync, which does not grow it
+    bytes(shared + PAGE_SIZE, 10).fill(0xbb);
+    bytes(shared + FILE_LEN, 10).fill(0xcc);
+    assert_eq!(msync(shared, MAP_LEN, MsyncFlags::SYNC), 0);
+    let mut buf = [0u8; MAP_LEN];
+    assert_eq!(read_file(&mut buf), FILE_LEN);
+    assert!(buf[PAGE_SIZE..PAGE_SIZE + 10].iter().all(|&b| b == 0xbb));
+    assert_eq!(buf[0], pattern(0));
+    assert_eq!(buf[2 * PAGE_SIZE], pattern(2 * PAGE_SIZE));
+
+    // a child shares the pages of the shared mapping and writes them back on exit,
+    // its private pages are its own
+    let pid = fork();
+    if pid == 0 {
+        assert!(bytes(private, 10).iter().all(|&b| b == 0xaa));
+        bytes(private + PAGE_SIZE + 100, 10).fill(0xdd);
+        bytes(shared + 2 * PAGE_SIZE, 10).fill(0xee);
+        exit(0);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    assert!(bytes(shared + 2 * PAGE_SIZE, 10).iter().all(|&b| b == 0xee));
+    assert_eq!(bytes(private + PAGE_SIZE + 100, 1)[0], pattern(PAGE_SIZE + 100));
+    assert_eq!(read_file(&mut buf), FILE_LEN);
+    assert!(buf[2 * PAGE_SIZE..2 * PAGE_SIZE + 10].iter().all(|&b| b == 0xee));
+
+    // and on munmap
+    bytes(shared, 10).fill(0x11);
+    assert_eq!(munmap(shared, MAP_LEN), 0);
+    assert_eq!(read_file(&mut buf), FILE_LEN);
+    assert!(buf[..10].iter().all(|&b| b == 0x11));
+    assert!(bytes(private, 10).iter().all(|&b| b == 0xaa));
+    assert_eq!(munmap(private, MAP_LEN), 0);
+    assert_eq!(msync(shared, MAP_LEN, MsyncFlags::SYNC), Errno::ENOMEM.as_ret());
+
+    // all the mappings of the file share its pages, whatever the fd,
+    // the offset and the process
+    let fd2 = open(FILE, OpenFlags::RDWR);
+    assert!(fd2 > 0);
+    let first = map(fd, prot, MapFlags::SHARED);
+    let second = mmap_file(0, PAGE_SIZE, prot, MapFlags::SHARED, fd2 as usize, PAGE_SIZE);
+    assert!(second > 0);
+    let second = second as usize;
+    bytes(first + PAGE_SIZE, 10).fill(0x33);
+    assert!(bytes(second, 10).iter().all(|&b| b == 0x33));
+    bytes(second + 20, 10).fill(0x44);
+    assert!(bytes(first + PAGE_SIZE + 20, 10).iter().all(|&b| b == 0x44));
+    let pid = fork();
+    if pid == 0 {
+        let addr = map(fd, prot, MapFlags::SHARED);
+        bytes(addr + 2 * PAGE_SIZE, 10).fill(0x55);
+        exit(0);
+    }
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    assert!(bytes(first + 2 * PAGE_SIZE, 10).iter().all(|&b| b == 0x55));
+    assert_eq!(munmap(second, PAGE_SIZE), 0);
+    assert_eq!(munmap(first, MAP_LEN), 0);
+    close(fd2 as usize);
+    assert_eq!(read_file(&mut buf), FILE_LEN);
+    assert!(buf[PAGE_SIZE..PAGE_SIZE + 10].iter().all(|&b| b == 0x33));
+    assert!(buf[PAGE_SIZE + 20..PAGE_SIZE + 30].iter().all(|&b| b == 0x44));
+    assert!(buf[2 * PAGE_SIZE..2 * PAGE_SIZE + 10].iter().all(|&b| b == 0x55));
+
+    // a page all past the end of the file can not be accessed
+    let addr = mmap_file(0, 4 * PAGE_SIZE, prot, MapFlags::SHARED, fd, 0);
+    assert!(addr > 0);
+    let pid = fork();
+    if pid == 0 {
+        bytes(addr as usize + 3 * PAGE_SIZE, 1)[0] = 1;
+        exit(0);
+    }
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, -11);
+    close(fd);
+
+    // a file opened read-only can not be written through a shared mapping
+    let fd = open(FILE, OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let fd = fd as usize;
+    assert_eq!(
+        mmap_file(0, MAP_LEN, prot, MapFlags::SHARED, fd, 0),
+        Errno::EACCES.as_ret()
+    );
+    let addr = map(fd, prot, MapFlags::PRIVATE);
+    bytes(addr, 10).fill(0x22);
+    assert_eq!(munmap(addr, MAP_LEN), 0);
+    close(fd);
+    assert_eq!(read_file(&mut buf), FILE_LEN);
+    assert!(buf[..10].iter().all(|&b| b == 0x11));
+
+    println!("mmap_file_test passed!");
+    0
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{errno::Errno, exit, fork, mmap, munmap, waitpid, MapFlags, ProtFlags};
+
+const PAGE_SIZE: usize = 4096;
+/// the kernel image and the physical memory, mapped in the kernel space
+const KERNEL_START: usize = 0x8020_0000;
+const KERNEL_END: usize = 0x8800_0000;
+
+fn fill(addr: usize, len: usize, value: u8) {
+    let buf = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, len) };
+    buf.fill(value);
+}
+
+fn check(addr: usize, len: usize, value: u8) {
+    let buf = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    assert!(buf.iter().all(|&b| b == value));
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let prot = ProtFlags::READ | ProtFlags::WRITE;
+    let flags = MapFlags::ANONYMOUS | MapFlags::PRIVATE;
+
+    // anonymous memory comes zeroed
+    let addr = mmap(0, 4 * PAGE_SIZE, prot, flags);
+    assert!(addr > 0);
+    let addr = addr as usize;
+    check(addr, 4 * PAGE_SIZE, 0);
+    fill(addr, 4 * PAGE_SIZE, 0x5a);
+
+    // no replacing with MAP_FIXED
+    assert_eq!(
+        mmap(addr + PAGE_SIZE, PAGE_SIZE, prot, flags | MapFlags::FIXED),
+        Errno::EEXIST.as_ret()
+    );
+    // unaligned, empty, out of user space, file mappings
+    assert_eq!(
+        mmap(addr + 1, PAGE_SIZE, prot, flags | MapFlags::FIXED),
+        Errno::EINVAL.as_ret()
+    );
+    assert_eq!(mmap(0, 0, prot, flags), Errno::EINVAL.as_ret());
+    assert_eq!(
+        mmap(usize::MAX - 2 * PAGE_SIZE + 1, PAGE_SIZE, prot, flags | MapFlags::FIXED),
+        Errno::ENOMEM.as_ret()
+    );
+    assert_eq!(
+        mmap(0, PAGE_SIZE, prot, MapFlags::PRIVATE),
+        Errno::EINVAL.as_ret()
+    );
+    // nor where the kernel maps its pages global to all address spaces,
+    // a hint there is moved past them
+    assert_eq!(
+        mmap(KERNEL_START, PAGE_SIZE, prot, flags | MapFlags::FIXED),
+        Errno::ENOMEM.as_ret()
+    );
+    let hinted = mmap(KERNEL_START, PAGE_SIZE, prot, flags);
+    assert!(hinted > 0);
+    let hinted = hinted as usize;
+    assert!(!(KERNEL_START..KERNEL_END).contains(&hinted));
+    fill(hinted, PAGE_SIZE, 0xa5);
+    check(hinted, PAGE_SIZE, 0xa5);
+    assert_eq!(munmap(hinted, PAGE_SIZE), 0);
+
+    // punch a hole in the middle, the rest stays mapped
+    assert_eq!(munmap(addr + PAGE_SIZE, 2 * PAGE_SIZE), 0);
+    check(addr, PAGE_SIZE, 0x5a);
+    check(addr + 3 * PAGE_SIZE, PAGE_SIZE, 0x5a);
+
+    // the hole can be mapped again, fresh
+    assert_eq!(
+        mmap(addr + PAGE_SIZE, PAGE_SIZE, prot, flags | MapFlags::FIXED),
+        (addr + PAGE_SIZE) as isize
+    );
+    check(addr + PAGE_SIZE, PAGE_SIZE, 0);
+
+    // touching the rest of the hole kills the process
+    let pid = fork();
+    if pid == 0 {
+        check(addr + 2 * PAGE_SIZE, PAGE_SIZE, 0);
+        exit(0);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, -11);
+
+    assert_eq!(munmap(addr, 4 * PAGE_SIZE), 0);
+    println!("mmap_test passed!");
+    0
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use core::arch::asm;
+use user_lib::{
+    close, errno::Errno, exit, fork, mmap, mmap_file, mprotect, munmap, open, waitpid, write,
+    MapFlags, OpenFlags, ProtFlags,
+};
+
+const PAGE_SIZE: usize = 4096;
+
+/// run `f` in a child and return its exit code
+fn in_child(f: impl FnOnce()) -> i32 {
+    let pid = fork();
+    if pid == 0 {
+        f();
+        exit(0);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    exit_code
+}
+
+fn poke(addr: usize) {
+    unsafe { (addr as *mut u8).write_volatile(1) };
+}
+
+fn call(addr: usize) -> usize {
+    let f: extern "C" fn() -> usize = unsafe { core::mem::transmute(addr) };
+    f()
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let rw = ProtFlags::READ | ProtFlags::WRITE;
+    let flags = MapFlags::ANONYMOUS | MapFlags::PRIVATE;
+    let addr = mmap(0, 3 * PAGE_SIZE, rw, flags);
+    assert!(addr > 0);
+    let addr = addr as usize;
+    poke(addr);
+    poke(addr + PAGE_SIZE);
+
+    // only the middle page becomes read-only
+    assert_eq!(mprotect(addr + PAGE_SIZE, PAGE_SIZE, ProtFlags::READ), 0);
+    assert_eq!(
+        unsafe { ((addr + PAGE_SIZE) as *const u8).read_volatile() },
+        1
+    );
+    assert_eq!(in_child(|| poke(addr + PAGE_SIZE)), -11);
+    assert_eq!(in_child(|| poke(addr)), 0);
+    assert_eq!(in_child(|| poke(addr + 2 * PAGE_SIZE)), 0);
+    // and writable again
+    assert_eq!(mprotect(addr, 3 * PAGE_SIZE, rw), 0);
+    poke(addr + PAGE_SIZE);
+
+    // code written to a page runs once it is executable
+    let code: [u32; 2] = [
+        0x02a0_0513, // li a0, 42
+        0x0000_8067, // ret
+    ];
+    let words = addr as *mut u32;
+    for (i, insn) in code.iter().enumerate() {
+        unsafe { words.add(i).write_volatile(*insn) };
+    }
+    unsafe { asm!("fence.i") };
+    assert_eq!(in_child(|| assert_eq!(call(addr), 42)), -11);
+    assert_eq!(
+        mprotect(addr, PAGE_SIZE, ProtFlags::READ | ProtFlags::EXEC),
+        0
+    );
+    assert_eq!(call(addr), 42);
+    assert_eq!(in_child(|| poke(addr)), -11);
+
+    // the whole range must be mapped
+    assert_eq!(munmap(addr + 2 * PAGE_SIZE, PAGE_SIZE), 0);
+    assert_eq!(mprotect(addr, 3 * PAGE_SIZE, rw), Errno::ENOMEM.as_ret());
+    assert_eq!(mprotect(addr + 1, PAGE_SIZE, rw), Errno::EINVAL.as_ret());
+    assert_eq!(munmap(addr, 2 * PAGE_SIZE), 0);
+
+    // a shared mapping of a read-only file stays read-only
+    let fd = open("mprotect_file\0", OpenFlags::CREATE | OpenFlags::WRONLY);
+    assert!(fd > 0);
+    assert_eq!(write(fd as usize, &[7u8; 16]), 16);
+    close(fd as usize);
+    let fd = open("mprotect_file\0", OpenFlags::RDONLY);
+    assert!(fd > 0);
+    let file = mmap_file(
+        0,
+        PAGE_SIZE,
+        ProtFlags::READ,
+        MapFlags::SHARED,
+        fd as usize,
+        0,
+    );
+    assert!(file > 0);
+    let file = file as usize;
+    assert_eq!(mprotect(file, PAGE_SIZE, rw), Errno::EACCES.as_ret());
+    assert_eq!(unsafe { (file as *const u8).read_volatile() }, 7);
+    assert_eq!(munmap(file, PAGE_SIZE), 0);
+    close(fd as usize);
+
+    println!("mprotect_test passed!");
+    0
+}
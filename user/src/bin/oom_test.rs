@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{exit, fork, mmap, munmap, waitpid, MapFlags, ProtFlags};
+
+const PAGE_SIZE: usize = 4096;
+/// more than the physical memory and the swap disk together
+const HOG_LEN: usize = 512 << 20;
+/// fits in memory again once the hog is gone
+const LEN: usize = 16 << 20;
+
+/// map `len` bytes and touch every page, return the address
+fn touch(len: usize) -> usize {
+    let prot = ProtFlags::READ | ProtFlags::WRITE;
+    let addr = mmap(0, len, prot, MapFlags::ANONYMOUS | MapFlags::PRIVATE);
+    assert!(addr > 0);
+    let addr = addr as usize;
+    for i in 0..len / PAGE_SIZE {
+        unsafe { ((addr + i * PAGE_SIZE) as *mut usize).write_volatile(i) };
+    }
+    addr
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // the hog is the largest task, so the OOM killer picks it
+    let pid = fork();
+    if pid == 0 {
+        touch(HOG_LEN);
+        println!("the hog survived");
+        exit(0);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, -9);
+
+    // and its frames are all back
+    let addr = touch(LEN);
+    for i in 0..LEN / PAGE_SIZE {
+        assert_eq!(
+            unsafe { ((addr + i * PAGE_SIZE) as *const usize).read_volatile() },
+            i
+        );
+    }
+    assert_eq!(munmap(addr, LEN), 0);
+    println!("oom_test passed!");
+    0
+}
@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::vec::Vec;
+use user_lib::errno::Errno;
+use user_lib::{close, exec, fork, open, waitpid, write, OpenFlags};
+
+const IMAGE: &str = "pie_test_image\0";
+const R_RISCV_64: u64 = 2;
+const R_RISCV_RELATIVE: u64 = 3;
+/// the exit code of the image when its relocation was applied
+const RELOCATED: i32 = 42;
+
+// layout of the image, one RWX segment mapping the whole file at 0
+const PHDRS: usize = 0x40;
+const PHDR_SIZE: usize = 56;
+const INTERP: usize = 0x120;
+const DYNAMIC: usize = 0x130;
+const RELA: usize = 0x170;
+const CODE: usize = 0x190;
+const WORD: usize = 0x1b0;
+const END: usize = 0x1b8;
+
+fn put(image: &mut Vec<u8>, at: usize, bytes: &[u8]) {
+    image[at..at + bytes.len()].copy_from_slice(bytes);
+}
+
+fn phdr(image: &mut Vec<u8>, index: usize, type_: u32, flags: u32, at: usize, size: usize) {
+    let base = PHDRS + index * PHDR_SIZE;
+    put(image, base, &type_.to_le_bytes());
+    put(image, base + 4, &flags.to_le_bytes());
+    for (i, value) in [at, at, at, size, size, 0x1000].into_iter().enumerate() {
+        put(image, base + 8 + i * 8, &(value as u64).to_le_bytes());
+    }
+}
+
+/// A position independent program exiting with RELOCATED if the word at WORD,
+/// which has a `rel_type` relocation, holds the address of its code,
+/// with PT_INTERP naming the dynamic loader if `interp`.
+fn image(interp: bool, rel_type: u64) -> Vec<u8> {
+    let mut image = Vec::new();
+    image.resize(END, 0);
+    let phnum = if interp { 4 } else { 3 };
+    // ELF64 header, ET_DYN for RISC-V
+    put(&mut image, 0, b"\x7fELF\x02\x01\x01");
+    put(&mut image, 16, &3u16.to_le_bytes());
+    put(&mut image, 18, &243u16.to_le_bytes());
+    put(&mut image, 20, &1u32.to_le_bytes());
+    put(&mut image, 24, &(CODE as u64).to_le_bytes());
+    put(&mut image, 32, &(PHDRS as u64).to_le_bytes());
+    put(&mut image, 52, &64u16.to_le_bytes());
+    put(&mut image, 54, &(PHDR_SIZE as u16).to_le_bytes());
+    put(&mut image, 56, &(phnum as u16).to_le_bytes());
+    put(&mut image, 58, &64u16.to_le_bytes());
+    phdr(&mut image, 0, 6, 4, PHDRS, phnum * PHDR_SIZE); // PT_PHDR
+    phdr(&mut image, 1, 1, 7, 0, END); // PT_LOAD
+    phdr(&mut image, 2, 2, 6, DYNAMIC, 64); // PT_DYNAMIC
+    if interp {
+        phdr(&mut image, 3, 3, 4, INTERP, 3); // PT_INTERP
+        put(&mut image, INTERP, b"ld\0");
+    }
+    // DT_RELA, DT_RELASZ, DT_RELAENT, DT_NULL
+    for (i, (tag, value)) in [(7, RELA), (8, 24), (9, 24), (0, 0)]
+        .into_iter()
+        .enumerate()
+    {
+        put(&mut image, DYNAMIC + i * 16, &(tag as u64).to_le_bytes());
+        put(
+            &mut image,
+            DYNAMIC + i * 16 + 8,
+            &(value as u64).to_le_bytes(),
+        );
+    }
+    put(&mut image, RELA, &(WORD as u64).to_le_bytes());
+    put(&mut image, RELA + 8, &rel_type.to_le_bytes());
+    put(&mut image, RELA + 16, &(CODE as u64).to_le_bytes());
+    let code: [u32; 6] = [
+        0x0000_0297,                             // auipc t0, 0
+        ((WORD - CODE) as u32) << 20 | 0x2_b303, // ld t1, WORD-CODE(t0)
+        0x4053_0533,                             // sub a0, t1, t0
+        (RELOCATED as u32) << 20 | 0x5_0513,     // addi a0, a0, RELOCATED
+        0x05d0_0893,                             // li a7, 93
+        0x0000_0073,                             // ecall
+    ];
+    for (i, inst) in code.iter().enumerate() {
+        put(&mut image, CODE + i * 4, &inst.to_le_bytes());
+    }
+    image
+}
+
+/// Write `image` out and run it, return the exit code.
+fn run(image: &[u8]) -> i32 {
+    let fd = open(
+        IMAGE,
+        OpenFlags::CREATE | OpenFlags::WRONLY | OpenFlags::TRUNC,
+    );
+    assert!(fd >= 0);
+    assert_eq!(write(fd as usize, image), image.len() as isize);
+    close(fd as usize);
+    let pid = fork();
+    if pid == 0 {
+        let ret = exec(IMAGE, &[IMAGE.as_ptr(), core::ptr::null()]);
+        // exit with the error of exec
+        user_lib::exit(ret as i32);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    exit_code
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // relocated by the kernel
+    assert_eq!(run(&image(false, R_RISCV_RELATIVE)), RELOCATED);
+    // relocated by the dynamic loader
+    assert_eq!(run(&image(true, R_RISCV_RELATIVE)), RELOCATED);
+    // the kernel does not look up symbols
+    assert_eq!(
+        run(&image(false, R_RISCV_64)),
+        Errno::ENOEXEC.as_ret() as i32
+    );
+    println!("pie_test passed!");
+    0
+}
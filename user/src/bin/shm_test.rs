@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{
+    errno::Errno, exit, fork, shmat, shmctl, shmdt, shmget, waitpid, ShmFlags, IPC_PRIVATE,
+    IPC_RMID,
+};
+
+const KEY: usize = 0x5348;
+/// large enough that copying it through a pipe would hurt
+const SIZE: usize = 64 * 4096;
+
+fn words(addr: usize) -> &'static mut [usize] {
+    unsafe { core::slice::from_raw_parts_mut(addr as *mut usize, SIZE / 8) }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    // looking up a key nobody created
+    assert_eq!(shmget(KEY, SIZE, ShmFlags::empty()), Errno::ENOENT.as_ret());
+    let id = shmget(KEY, SIZE, ShmFlags::IPC_CREAT | ShmFlags::IPC_EXCL);
+    assert!(id >= 0);
+    let id = id as usize;
+    assert_eq!(
+        shmget(KEY, SIZE, ShmFlags::IPC_CREAT | ShmFlags::IPC_EXCL),
+        Errno::EEXIST.as_ret()
+    );
+    assert_eq!(shmget(KEY, SIZE * 2, ShmFlags::empty()), Errno::EINVAL.as_ret());
+    assert_eq!(shmget(KEY, SIZE, ShmFlags::empty()), id as isize);
+
+    let addr = shmat(id, 0, ShmFlags::empty());
+    assert!(addr > 0);
+    let addr = addr as usize;
+    assert!(words(addr).iter().all(|&w| w == 0));
+
+    // the child attaches the segment by key and fills it
+    let pid = fork();
+    if pid == 0 {
+        let id = shmget(KEY, 0, ShmFlags::empty());
+        assert!(id >= 0);
+        let child_addr = shmat(id as usize, 0, ShmFlags::empty());
+        assert!(child_addr > 0 && child_addr as usize != addr);
+        for (i, w) in words(child_addr as usize).iter_mut().enumerate() {
+            *w = i * 3;
+        }
+        assert_eq!(shmdt(child_addr as usize), 0);
+        // the inherited attachment is the same memory too
+        words(addr)[0] = 0x1234;
+        exit(0);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    assert_eq!(exit_code, 0);
+    let buf = words(addr);
+    assert_eq!(buf[0], 0x1234);
+    assert!(buf.iter().enumerate().skip(1).all(|(i, &w)| w == i * 3));
+
+    // removed segments stay attached until detached
+    assert_eq!(shmctl(id, IPC_RMID), 0);
+    assert_eq!(shmget(KEY, SIZE, ShmFlags::empty()), Errno::ENOENT.as_ret());
+    assert_eq!(shmat(id, 0, ShmFlags::empty()), Errno::EINVAL.as_ret());
+    assert_eq!(buf[1], 3);
+    assert_eq!(shmdt(addr), 0);
+    assert_eq!(shmdt(addr), Errno::EINVAL.as_ret());
+
+    // private segments are never shared by key
+    let a = shmget(IPC_PRIVATE, 4096, ShmFlags::empty());
+    let b = shmget(IPC_PRIVATE, 4096, ShmFlags::empty());
+    assert!(a >= 0 && b >= 0 && a != b);
+    assert_eq!(shmctl(a as usize, IPC_RMID), 0);
+    assert_eq!(shmctl(b as usize, IPC_RMID), 0);
+    println!("shm_test passed!");
+    0
+}
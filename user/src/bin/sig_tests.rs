@@ -74,7 +74,7 @@ fn user_sig_test_restore() {
 
 fn kernel_sig_test_ignore() {
     sigprocmask(SignalFlags::SIGSTOP.bits() as u32);
-    if kill(getpid() as usize, SignalFlags::SIGSTOP.bits()) < 0 {
+    if kill(getpid() as usize, SIGSTOP) < 0 {
         println!("kill faild\n");
         exit(-1);
     }
@@ -94,6 +94,23 @@ fn kernel_sig_test_stop_cont() {
     }
 }
 
+fn kernel_sig_test_kill_sleeping() {
+    let pid = fork();
+    if pid == 0 {
+        sleep(10_000);
+        exit(0);
+    } else {
+        // let the child fall asleep
+        sleep(100);
+        let start = get_time();
+        kill(pid as usize, SIGKILL);
+        let mut exit_code = 0;
+        assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+        // the child died without sleeping to the end
+        assert!(get_time() - start < 5_000);
+    }
+}
+
 fn kernel_sig_test_failignorekill() {
     let mut new = SignalAction::default();
     let mut old = SignalAction::default();
@@ -162,7 +179,7 @@ fn run(f: fn()) -> bool {
 
 #[no_mangle]
 pub fn main() -> i32 {
-    let tests: [(fn(), &str); 8] = [
+    let tests: [(fn(), &str); 9] = [
         (user_sig_test_failsignum, "user_sig_test_failsignum"),
         (user_sig_test_kill, "user_sig_test_kill"),
         (
@@ -172,6 +189,10 @@ pub fn main() -> i32 {
         (user_sig_test_restore, "user_sig_test_restore"),
         (kernel_sig_test_ignore, "kernel_sig_test_ignore"),
         (kernel_sig_test_stop_cont, "kernel_sig_test_stop_cont"),
+        (
+            kernel_sig_test_kill_sleeping,
+            "kernel_sig_test_kill_sleeping",
+        ),
         (
             kernel_sig_test_failignorekill,
             "kernel_sig_test_failignorekill",
@@ -4,7 +4,13 @@
 #[macro_use]
 extern crate user_lib;
 
-use user_lib::{get_time, sleep};
+use user_lib::{clock_gettime, get_time, sleep, usleep, TimeSpec, CLOCK_MONOTONIC};
+
+fn now_us() -> usize {
+    let mut ts = TimeSpec::default();
+    clock_gettime(CLOCK_MONOTONIC, &mut ts);
+    ts.tv_sec * 1_000_000 + ts.tv_nsec / 1000
+}
 
 #[no_mangle]
 pub fn main() -> i32 {
@@ -18,6 +24,13 @@ pub fn main() -> i32 {
         end,
         end - start
     );
+    // sleeping is in microseconds underneath
+    let start = now_us();
+    usleep(1500);
+    let delta = now_us() - start;
+    // less a microsecond lost to the rounding of the clocks
+    assert!(delta + 1 >= 1500, "woke up after {}us", delta);
+    println!("usleep 1500us took {}us", delta);
     println!("r_sleep passed!");
     0
 }
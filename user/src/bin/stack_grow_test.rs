@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+extern crate alloc;
+
+use alloc::vec;
+use core::arch::asm;
+use user_lib::{
+    errno::Errno, exec, exit, fork, getrlimit, setrlimit, waitpid, RLimit, RLIMIT_STACK,
+};
+
+/// touches a page of stack in every call
+#[inline(never)]
+fn recurse(depth: usize) -> usize {
+    let mut buf = [0u8; 4096];
+    buf[0] = depth as u8;
+    let buf = core::hint::black_box(&mut buf);
+    if depth == 0 {
+        return buf[0] as usize;
+    }
+    recurse(depth - 1) + buf[0] as usize
+}
+
+/// run `f` in a child, return its exit code
+fn in_child(f: fn()) -> i32 {
+    let pid = fork();
+    if pid == 0 {
+        f();
+        exit(0);
+    }
+    let mut exit_code = 0;
+    assert_eq!(waitpid(pid as usize, &mut exit_code), pid);
+    exit_code
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut limit = RLimit::default();
+    assert_eq!(getrlimit(RLIMIT_STACK, &mut limit), 0);
+    assert_eq!(limit.rlim_cur, 8 << 20);
+    assert!(limit.rlim_max >= limit.rlim_cur);
+
+    // 1 MiB of stack, far beyond the pages mapped at first
+    let depth = 256;
+    let sum = recurse(depth);
+    assert_eq!(sum, (0..=depth).map(|d| d as u8 as usize).sum::<usize>());
+
+    // far below sp is not the stack, though within the limit
+    let exit_code = in_child(|| {
+        let sp: usize;
+        unsafe {
+            asm!("mv {}, sp", out(reg) sp);
+            ((sp - (1 << 20)) as *mut u8).write_volatile(1);
+        }
+    });
+    assert_eq!(exit_code, -11);
+
+    // a smaller limit stops the same recursion
+    let exit_code = in_child(|| {
+        let mut small = RLimit::default();
+        getrlimit(RLIMIT_STACK, &mut small);
+        small.rlim_cur = 64 << 10;
+        assert_eq!(setrlimit(RLIMIT_STACK, &small), 0);
+        recurse(256);
+    });
+    assert_eq!(exit_code, -11);
+
+    // the soft limit is at most the hard one, which can't be raised
+    let bad = RLimit {
+        rlim_cur: limit.rlim_max + 1,
+        rlim_max: limit.rlim_max,
+    };
+    assert_eq!(setrlimit(RLIMIT_STACK, &bad), Errno::EINVAL.as_ret());
+    let bad = RLimit {
+        rlim_cur: limit.rlim_cur,
+        rlim_max: limit.rlim_max + 1,
+    };
+    assert_eq!(setrlimit(RLIMIT_STACK, &bad), Errno::EPERM.as_ret());
+
+    // a lowered hard limit stays, for the soft limit too
+    let exit_code = in_child(|| {
+        let lowered = RLimit {
+            rlim_cur: 64 << 10,
+            rlim_max: 64 << 10,
+        };
+        assert_eq!(setrlimit(RLIMIT_STACK, &lowered), 0);
+        let mut limit = RLimit::default();
+        assert_eq!(getrlimit(RLIMIT_STACK, &mut limit), 0);
+        assert_eq!((limit.rlim_cur, limit.rlim_max), (64 << 10, 64 << 10));
+        let raised = RLimit {
+            rlim_cur: 128 << 10,
+            rlim_max: 128 << 10,
+        };
+        assert_eq!(setrlimit(RLIMIT_STACK, &raised), Errno::EPERM.as_ret());
+        let raised = RLimit {
+            rlim_cur: 128 << 10,
+            rlim_max: 64 << 10,
+        };
+        assert_eq!(setrlimit(RLIMIT_STACK, &raised), Errno::EINVAL.as_ret());
+    });
+    assert_eq!(exit_code, 0);
+
+    // exec fails when the arguments do not fit in the stack limit
+    let exit_code = in_child(|| {
+        let small = RLimit {
+            rlim_cur: 16 << 10,
+            rlim_max: 16 << 10,
+        };
+        assert_eq!(setrlimit(RLIMIT_STACK, &small), 0);
+        let mut arg = vec![b'a'; 3000];
+        arg.push(0);
+        let mut args = vec![arg.as_ptr(); 16];
+        args.push(core::ptr::null());
+        assert_eq!(exec("sleep_simple\0", &args), Errno::E2BIG.as_ret());
+    });
+    assert_eq!(exit_code, 0);
+
+    println!("stack_grow_test passed!");
+    0
+}
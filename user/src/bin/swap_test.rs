@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user_lib;
+
+use user_lib::{mmap, munmap, MapFlags, ProtFlags};
+
+const PAGE_SIZE: usize = 4096;
+/// more than the physical memory, some pages have to be swapped out
+const LEN: usize = 144 << 20;
+
+fn word(addr: usize, i: usize) -> *mut usize {
+    (addr + i * PAGE_SIZE) as *mut usize
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let prot = ProtFlags::READ | ProtFlags::WRITE;
+    let flags = MapFlags::ANONYMOUS | MapFlags::PRIVATE;
+    let addr = mmap(0, LEN, prot, flags);
+    assert!(addr > 0);
+    let addr = addr as usize;
+    let pages = LEN / PAGE_SIZE;
+
+    for i in 0..pages {
+        unsafe { word(addr, i).write_volatile(i * 7 + 1) };
+    }
+    // twice, so pages swapped in come back clean and get swapped out again
+    for _ in 0..2 {
+        for i in 0..pages {
+            assert_eq!(unsafe { word(addr, i).read_volatile() }, i * 7 + 1);
+        }
+    }
+    println!("{} pages written and read back", pages);
+
+    assert_eq!(munmap(addr, LEN), 0);
+    println!("swap_test passed!");
+    0
+}
@@ -15,6 +15,7 @@ const LINE_START: &str = ">> ";
 use alloc::string::String;
 use alloc::vec::Vec;
 use user_lib::console::getchar;
+use user_lib::errno::check;
 use user_lib::{close, dup, exec, fork, open, pipe, waitpid, OpenFlags};
 
 #[derive(Debug)]
@@ -160,12 +161,14 @@ pub fn main() -> i32 {
 
                                 // redirect non-pipe input
                                 if !input.is_empty() {
-                                    let input_fd = open(input.as_str(), OpenFlags::RDONLY);
-                                    if input_fd == -1 {
-                                        println!("Error when opening file {}", input);
-                                        return -4;
-                                    }
-                                    let input_fd = input_fd as usize;
+                                    let input_fd =
+                                        match check(open(input.as_str(), OpenFlags::RDONLY)) {
+                                            Ok(fd) => fd,
+                                            Err(err) => {
+                                                println!("Error when opening file {}: {}", input, err);
+                                                return -4;
+                                            }
+                                        };
                                     // close 0, dup as 0, close old fd
                                     // redirect stdin to input file
                                     close(0);
@@ -175,15 +178,16 @@ pub fn main() -> i32 {
 
                                 // redirect non-pipe output
                                 if !output.is_empty() {
-                                    let output_fd = open(
+                                    let output_fd = match check(open(
                                         output.as_str(),
                                         OpenFlags::CREATE | OpenFlags::WRONLY,
-                                    );
-                                    if output_fd == -1 {
-                                        println!("Error when opening file {}", output);
-                                        return -4;
-                                    }
-                                    let output_fd = output_fd as usize;
+                                    )) {
+                                        Ok(fd) => fd,
+                                        Err(err) => {
+                                            println!("Error when opening file {}: {}", output, err);
+                                            return -4;
+                                        }
+                                    };
                                     // close 1, dup as 1, close old fd
                                     // redirect stdout to output file
                                     close(1);
@@ -216,8 +220,10 @@ pub fn main() -> i32 {
                                 }
 
                                 // execute new application
-                                if exec(args_copy[0].as_str(), args_addr.as_slice()) == -1 {
-                                    println!("Error when executing!");
+                                if let Err(err) =
+                                    check(exec(args_copy[0].as_str(), args_addr.as_slice()))
+                                {
+                                    println!("Error when executing: {}", err);
                                     return -4;
                                 }
                                 unreachable!();
@@ -9,6 +9,10 @@ extern crate user_lib;
 
 // item of TESTS : app_name(argv_0), argv_1, argv_2, argv_3, exit_code
 static SUCC_TESTS: &[(&str, &str, &str, &str, i32)] = &[
+    ("aslr_test\0", "\0", "\0", "\0", 0),
+    ("brk_test\0", "\0", "\0", "\0", 0),
+    ("clock_test\0", "\0", "\0", "\0", 0),
+    ("errno_test\0", "\0", "\0", "\0", 0),
     ("exit\0", "\0", "\0", "\0", 0),
     ("fantastic_text\0", "\0", "\0", "\0", 0),
     ("forktest_simple\0", "\0", "\0", "\0", 0),
@@ -16,13 +20,22 @@ static SUCC_TESTS: &[(&str, &str, &str, &str, i32)] = &[
     ("forktest2\0", "\0", "\0", "\0", 0),
     ("forktree\0", "\0", "\0", "\0", 0),
     ("hello_world\0", "\0", "\0", "\0", 0),
+    ("lazy_test\0", "\0", "\0", "\0", 0),
     ("matrix\0", "\0", "\0", "\0", 0),
+    ("mmap_file_test\0", "\0", "\0", "\0", 0),
+    ("mmap_test\0", "\0", "\0", "\0", 0),
+    ("mprotect_test\0", "\0", "\0", "\0", 0),
+    ("oom_test\0", "\0", "\0", "\0", 0),
+    ("pie_test\0", "\0", "\0", "\0", 0),
+    ("shm_test\0", "\0", "\0", "\0", 0),
     ("sleep_simple\0", "\0", "\0", "\0", 0),
     ("sleep\0", "\0", "\0", "\0", 0),
+    ("stack_grow_test\0", "\0", "\0", "\0", 0),
+    ("swap_test\0", "\0", "\0", "\0", 0),
     ("yield\0", "\0", "\0", "\0", 0),
 ];
 
-static FAIL_TESTS: &[(&str, &str, &str, &str, i32)] = &[("stack_overflow\0", "\0", "\0", "\0", -2)];
+static FAIL_TESTS: &[(&str, &str, &str, &str, i32)] = &[("stack_overflow\0", "\0", "\0", "\0", -11)];
 
 use user_lib::{exec, fork, waitpid};
 
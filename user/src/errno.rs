@@ -0,0 +1,98 @@
+use core::fmt::{self, Debug, Display, Formatter};
+
+/// Error number of a failed syscall.
+/// The kernel returns it negated, the same as Linux.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub isize);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(1);
+    pub const ENOENT: Errno = Errno(2);
+    pub const ESRCH: Errno = Errno(3);
+    pub const EINTR: Errno = Errno(4);
+    pub const EIO: Errno = Errno(5);
+    pub const E2BIG: Errno = Errno(7);
+    pub const ENOEXEC: Errno = Errno(8);
+    pub const EBADF: Errno = Errno(9);
+    pub const ECHILD: Errno = Errno(10);
+    pub const EAGAIN: Errno = Errno(11);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EACCES: Errno = Errno(13);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EBUSY: Errno = Errno(16);
+    pub const EEXIST: Errno = Errno(17);
+    pub const ENODEV: Errno = Errno(19);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const EFBIG: Errno = Errno(27);
+    pub const ENOSPC: Errno = Errno(28);
+    pub const ESPIPE: Errno = Errno(29);
+    pub const EPIPE: Errno = Errno(32);
+    pub const ERANGE: Errno = Errno(34);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const ETIMEDOUT: Errno = Errno(110);
+
+    /// the raw return value of a syscall failing with this errno
+    pub fn as_ret(&self) -> isize {
+        -self.0
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Self::EPERM => "Operation not permitted",
+            Self::ENOENT => "No such file or directory",
+            Self::ESRCH => "No such process",
+            Self::EINTR => "Interrupted system call",
+            Self::EIO => "I/O error",
+            Self::E2BIG => "Argument list too long",
+            Self::ENOEXEC => "Exec format error",
+            Self::EBADF => "Bad file descriptor",
+            Self::ECHILD => "No child processes",
+            Self::EAGAIN => "Try again",
+            Self::ENOMEM => "Out of memory",
+            Self::EACCES => "Permission denied",
+            Self::EFAULT => "Bad address",
+            Self::EBUSY => "Device or resource busy",
+            Self::EEXIST => "File exists",
+            Self::ENODEV => "No such device",
+            Self::EISDIR => "Is a directory",
+            Self::EINVAL => "Invalid argument",
+            Self::EMFILE => "Too many open files",
+            Self::EFBIG => "File too large",
+            Self::ENOSPC => "No space left on device",
+            Self::ESPIPE => "Illegal seek",
+            Self::EPIPE => "Broken pipe",
+            Self::ERANGE => "Math result not representable",
+            Self::ENAMETOOLONG => "File name too long",
+            Self::ENOSYS => "Function not implemented",
+            Self::ETIMEDOUT => "Connection timed out",
+            _ => "Unknown error",
+        }
+    }
+}
+
+impl Debug for Errno {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("Errno({})", self.0))
+    }
+}
+
+impl Display for Errno {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("{} (errno {})", self.description(), self.0))
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Errno>;
+
+/// Turn the raw return value of a syscall into a `Result`,
+/// negative values being errors.
+pub fn check(ret: isize) -> Result<usize> {
+    if ret < 0 {
+        Err(Errno(-ret))
+    } else {
+        Ok(ret as usize)
+    }
+}
@@ -7,19 +7,58 @@ extern crate alloc;
 use alloc::vec::Vec;
 use bitflags::bitflags;
 use buddy_system_allocator::LockedHeap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{null_mut, NonNull},
+};
 use syscall::*;
 
 #[macro_use]
 pub mod console;
+pub mod errno;
 mod lang_items;
 mod syscall;
 
+use errno::Errno;
+
 const USER_HEAP_SIZE: usize = 16384;
+/// the heap grows by at least this much through brk
+const HEAP_GROW_SIZE: usize = 16384;
+const PAGE_SIZE: usize = 4096;
 
 static mut HEAP_SPACE: [u8; USER_HEAP_SIZE] = [0; USER_HEAP_SIZE];
 
+/// Starts with HEAP_SPACE, and extends itself with sbrk when it runs out
+struct UserHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for UserHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.0.lock();
+        if let Ok(ptr) = heap.alloc(layout) {
+            return ptr.as_ptr();
+        }
+        // the buddy allocator needs a block aligned to its size,
+        // twice the size always contains one
+        let size = layout.size().max(layout.align()).next_power_of_two() * 2;
+        let size = (size.max(HEAP_GROW_SIZE) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let start = sbrk(size as isize);
+        if start < 0 {
+            return null_mut();
+        }
+        heap.add_to_heap(start as usize, start as usize + size);
+        heap.alloc(layout).map_or(null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
 #[global_allocator]
-static HEAP: LockedHeap = LockedHeap::empty();
+static HEAP: UserHeap = UserHeap(LockedHeap::empty());
+
+/// the auxiliary vector after argv, null for a process not started by exec
+static mut AUXV: usize = 0;
 
 #[no_mangle]
 #[link_section = ".text.entry"]
@@ -27,8 +66,12 @@ pub extern "C" fn _start(argc: usize, argv: usize) -> ! {
     // argc: a0
     // argv: a1
     unsafe {
-        HEAP.lock()
+        HEAP.0
+            .lock()
             .init(HEAP_SPACE.as_ptr() as usize, USER_HEAP_SIZE);
+        if argv != 0 {
+            AUXV = argv + (argc + 1) * core::mem::size_of::<usize>();
+        }
     }
 
     let mut v: Vec<&'static str> = Vec::new();
@@ -64,6 +107,81 @@ bitflags! {
     }
 }
 
+bitflags! {
+    pub struct ProtFlags: usize {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+bitflags! {
+    pub struct MapFlags: usize {
+        const SHARED = 1 << 0;
+        const PRIVATE = 1 << 1;
+        const FIXED = 1 << 4;
+        const ANONYMOUS = 1 << 5;
+    }
+}
+
+bitflags! {
+    pub struct MsyncFlags: usize {
+        const ASYNC = 1 << 0;
+        const INVALIDATE = 1 << 1;
+        const SYNC = 1 << 2;
+    }
+}
+
+bitflags! {
+    pub struct ShmFlags: usize {
+        const IPC_CREAT = 0o1000;
+        const IPC_EXCL = 0o2000;
+        const SHM_RDONLY = 0o10000;
+    }
+}
+
+bitflags! {
+    pub struct Personality: u32 {
+        /// exec does not randomize the address space layout
+        const ADDR_NO_RANDOMIZE = 0x0040000;
+    }
+}
+
+// keys of the auxiliary vector
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_BASE: usize = 7;
+pub const AT_ENTRY: usize = 9;
+
+/// the auxiliary vector, (key, value) pairs ending with AT_NULL
+pub fn auxv() -> *const usize {
+    unsafe { AUXV as *const usize }
+}
+/// the value of `key` in the auxiliary vector
+pub fn getauxval(key: usize) -> Option<usize> {
+    let mut entry = auxv();
+    if entry.is_null() {
+        return None;
+    }
+    loop {
+        let (k, v) = unsafe { (*entry, *entry.add(1)) };
+        if k == AT_NULL {
+            return None;
+        }
+        if k == key {
+            return Some(v);
+        }
+        entry = unsafe { entry.add(2) };
+    }
+}
+
+/// the key of a segment nobody else can look up
+pub const IPC_PRIVATE: usize = 0;
+pub const IPC_RMID: usize = 0;
+
 pub fn dup(fd: usize) -> isize {
     sys_dup(fd)
 }
@@ -76,6 +194,63 @@ pub fn close(fd: usize) -> isize {
     sys_close(fd)
 }
 
+pub fn mmap(addr: usize, len: usize, prot: ProtFlags, flags: MapFlags) -> isize {
+    sys_mmap(addr, len, prot.bits, flags.bits, usize::MAX, 0)
+}
+/// map `len` bytes of the file `fd` from `offset`, which is page aligned
+pub fn mmap_file(
+    addr: usize,
+    len: usize,
+    prot: ProtFlags,
+    flags: MapFlags,
+    fd: usize,
+    offset: usize,
+) -> isize {
+    sys_mmap(addr, len, prot.bits, flags.bits, fd, offset)
+}
+pub fn mprotect(addr: usize, len: usize, prot: ProtFlags) -> isize {
+    sys_mprotect(addr, len, prot.bits)
+}
+pub fn msync(addr: usize, len: usize, flags: MsyncFlags) -> isize {
+    sys_msync(addr, len, flags.bits)
+}
+pub fn munmap(addr: usize, len: usize) -> isize {
+    sys_munmap(addr, len)
+}
+
+/// set the program break, return the new one, which stays the same on failure
+pub fn brk(addr: usize) -> isize {
+    sys_brk(addr)
+}
+/// move the program break by `increment`, return the old one or -ENOMEM
+pub fn sbrk(increment: isize) -> isize {
+    let old_brk = sys_brk(0);
+    if increment == 0 {
+        return old_brk;
+    }
+    let new_brk = match (old_brk as usize).checked_add_signed(increment) {
+        Some(new_brk) => new_brk,
+        None => return Errno::ENOMEM.as_ret(),
+    };
+    if sys_brk(new_brk) as usize != new_brk {
+        return Errno::ENOMEM.as_ret();
+    }
+    old_brk
+}
+
+pub fn shmget(key: usize, size: usize, flags: ShmFlags) -> isize {
+    sys_shmget(key, size, flags.bits)
+}
+pub fn shmat(shmid: usize, addr: usize, flags: ShmFlags) -> isize {
+    sys_shmat(shmid, addr, flags.bits)
+}
+pub fn shmdt(addr: usize) -> isize {
+    sys_shmdt(addr)
+}
+pub fn shmctl(shmid: usize, cmd: usize) -> isize {
+    sys_shmctl(shmid, cmd, 0)
+}
+
 pub fn pipe(pipe_fd: &mut [usize]) -> isize {
     sys_pipe(pipe_fd)
 }
@@ -92,22 +267,42 @@ pub fn exit(code: i32) -> ! {
 pub fn yield_() {
     sys_yield()
 }
+/// milliseconds since boot
 pub fn get_time() -> isize {
-    sys_get_time()
+    let mut ts = TimeSpec::default();
+    sys_clock_gettime(CLOCK_MONOTONIC, &mut ts);
+    (ts.tv_sec * 1000 + ts.tv_nsec / 1_000_000) as isize
+}
+pub fn clock_gettime(clock_id: usize, ts: &mut TimeSpec) -> isize {
+    sys_clock_gettime(clock_id, ts)
+}
+pub fn gettimeofday(tv: &mut TimeVal) -> isize {
+    sys_gettimeofday(tv)
 }
 pub fn getpid() -> isize {
     sys_getpid()
 }
+/// set the personality flags, return the old ones
+pub fn personality(persona: Personality) -> isize {
+    sys_personality(persona.bits as usize)
+}
+pub fn getrlimit(resource: usize, rlim: &mut RLimit) -> isize {
+    sys_getrlimit(resource, rlim)
+}
+pub fn setrlimit(resource: usize, rlim: &RLimit) -> isize {
+    sys_setrlimit(resource, rlim)
+}
 pub fn fork() -> isize {
     sys_fork()
 }
 pub fn exec(path: &str, args: &[*const u8]) -> isize {
     sys_exec(path, args)
 }
+/// return the pid of the exited child, or -ECHILD if there is no child
 pub fn wait(exit_code: &mut i32) -> isize {
     loop {
         match sys_waitpid(-1, exit_code as *mut _) {
-            -2 => yield_(),
+            ret if ret == Errno::EAGAIN.as_ret() => yield_(),
             pid => return pid,
         }
     }
@@ -115,18 +310,45 @@ pub fn wait(exit_code: &mut i32) -> isize {
 pub fn waitpid(pid: usize, exit_code: &mut i32) -> isize {
     loop {
         match sys_waitpid(pid as isize, exit_code as *mut _) {
-            -2 => yield_(),
-            // -1 or a real pid
+            ret if ret == Errno::EAGAIN.as_ret() => yield_(),
+            // -ECHILD or a real pid
             exit_pid => return exit_pid,
         }
     }
 }
 
 pub fn sleep(period_ms: usize) {
-    let start = sys_get_time();
-    while sys_get_time() < start + period_ms as isize {
-        sys_yield();
-    }
+    sys_sleep(period_ms * 1000);
+}
+pub fn usleep(period_us: usize) {
+    sys_sleep(period_us);
+}
+
+/// the limit of the stack size, the only resource the kernel limits
+pub const RLIMIT_STACK: usize = 3;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RLimit {
+    pub rlim_cur: usize,
+    pub rlim_max: usize,
+}
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TimeSpec {
+    pub tv_sec: usize,
+    pub tv_nsec: usize,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TimeVal {
+    pub tv_sec: usize,
+    pub tv_usec: usize,
 }
 
 /// Action for a signal
@@ -249,7 +471,7 @@ pub fn gettid() -> isize {
 pub fn waittid(tid: usize) -> isize {
     loop {
         match sys_waittid(tid) {
-            -2 => {
+            ret if ret == Errno::EAGAIN.as_ret() => {
                 yield_();
             }
             exit_code => return exit_code,
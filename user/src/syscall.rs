@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use crate::SignalAction;
+use crate::{RLimit, SignalAction, TimeSpec, TimeVal};
 
 const SYSCALL_DUP: usize = 24;
 const SYSCALL_OPEN: usize = 56;
@@ -8,16 +8,30 @@ const SYSCALL_CLOSE: usize = 57;
 const SYSCALL_PIPE: usize = 59;
 const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
+const SYSCALL_PERSONALITY: usize = 92;
 const SYSCALL_EXIT: usize = 93;
+const SYSCALL_SLEEP: usize = 101;
+const SYSCALL_CLOCK_GETTIME: usize = 113;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_KILL: usize = 129;
 const SYSCALL_SIGACTION: usize = 134;
 const SYSCALL_SIGPROCMASK: usize = 135;
 const SYSCALL_SIGRETURN: usize = 139;
-const SYSCALL_GET_TIME: usize = 169;
+const SYSCALL_GETRLIMIT: usize = 163;
+const SYSCALL_SETRLIMIT: usize = 164;
+const SYSCALL_GETTIMEOFDAY: usize = 169;
 const SYSCALL_GETPID: usize = 172;
+const SYSCALL_SHMGET: usize = 194;
+const SYSCALL_SHMCTL: usize = 195;
+const SYSCALL_SHMAT: usize = 196;
+const SYSCALL_SHMDT: usize = 197;
 const SYSCALL_FORK: usize = 220;
+const SYSCALL_BRK: usize = 214;
+const SYSCALL_MUNMAP: usize = 215;
 const SYSCALL_EXEC: usize = 221;
+const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
+const SYSCALL_MSYNC: usize = 227;
 const SYSCALL_WAITPID: usize = 260;
 const SYSCALL_THREAD_CREATE: usize = 1000;
 const SYSCALL_GETTID: usize = 1001;
@@ -46,6 +60,23 @@ fn syscall(id: usize, args: [usize; 3]) -> isize {
     ret
 }
 
+fn syscall6(id: usize, args: [usize; 6]) -> isize {
+    let mut ret: isize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") args[0] => ret,
+            in("x11") args[1],
+            in("x12") args[2],
+            in("x13") args[3],
+            in("x14") args[4],
+            in("x15") args[5],
+            in("x17") id,
+        );
+    }
+    ret
+}
+
 pub fn sys_dup(fd: usize) -> isize {
     syscall(SYSCALL_DUP, [fd, 0, 0])
 }
@@ -81,20 +112,51 @@ pub fn sys_yield() {
     syscall(SYSCALL_YIELD, [0, 0, 0]);
 }
 
-/// return -1 when arguments are invalid
+/// return -ESRCH when the process does not exist,
+/// -EINVAL when the signal is invalid,
 /// elsewise return 0
 pub fn sys_kill(pid: usize, signal: i32) -> isize {
     syscall(SYSCALL_KILL, [pid, signal as usize, 0])
 }
 
-pub fn sys_get_time() -> isize {
-    syscall(SYSCALL_GET_TIME, [0, 0, 0])
+pub fn sys_sleep(period_us: usize) -> isize {
+    syscall(SYSCALL_SLEEP, [period_us, 0, 0])
+}
+
+/// return -EINVAL for an unknown clock
+pub fn sys_clock_gettime(clock_id: usize, ts: &mut TimeSpec) -> isize {
+    syscall(
+        SYSCALL_CLOCK_GETTIME,
+        [clock_id, ts as *mut TimeSpec as usize, 0],
+    )
+}
+
+pub fn sys_gettimeofday(tv: &mut TimeVal) -> isize {
+    syscall(SYSCALL_GETTIMEOFDAY, [tv as *mut TimeVal as usize, 0, 0])
 }
 
 pub fn sys_getpid() -> isize {
     syscall(SYSCALL_GETPID, [0, 0, 0])
 }
 
+pub fn sys_personality(persona: usize) -> isize {
+    syscall(SYSCALL_PERSONALITY, [persona, 0, 0])
+}
+
+pub fn sys_getrlimit(resource: usize, rlim: &mut RLimit) -> isize {
+    syscall(
+        SYSCALL_GETRLIMIT,
+        [resource, rlim as *mut RLimit as usize, 0],
+    )
+}
+
+pub fn sys_setrlimit(resource: usize, rlim: &RLimit) -> isize {
+    syscall(
+        SYSCALL_SETRLIMIT,
+        [resource, rlim as *const RLimit as usize, 0],
+    )
+}
+
 pub fn sys_fork() -> isize {
     syscall(SYSCALL_FORK, [0, 0, 0])
 }
@@ -114,6 +176,8 @@ pub fn sys_exec(path: &str, args: &[*const u8]) -> isize {
 /// if exit_code is 0, the exit code will not be stored
 ///
 /// return the pid of the child process that exited
+/// return -EAGAIN if the child process has not exited yet,
+/// and -ECHILD if there is no such child process
 pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
     syscall(SYSCALL_WAITPID, [pid as usize, exit_code as usize, 0])
 }
@@ -121,8 +185,8 @@ pub fn sys_waitpid(pid: isize, exit_code: *mut i32) -> isize {
 /// 功能：为当前进程设置某种信号的处理函数，同时保存设置之前的处理函数。
 /// 参数：signum 表示信号的编号，action 表示要设置成的处理函数的指针
 /// old_action 表示用于保存设置之前的处理函数的指针（SignalAction 结构稍后介绍）。
-/// 返回值：如果传入参数错误（比如传入的 action 或 old_action 为空指针或者）
-/// 信号类型不存在返回 -1 ，否则返回 0 。
+/// 返回值：如果传入的 action 或 old_action 为空指针返回 -EFAULT，
+/// 信号类型不存在或不可设置返回 -EINVAL，否则返回 0 。
 pub fn sys_sigaction(
     signum: i32,
     action: *const SignalAction,
@@ -134,7 +198,7 @@ pub fn sys_sigaction(
     )
 }
 
-/// return -1 if the mask is invalid
+/// return -EINVAL if the mask is invalid
 /// elsewise return the old mask
 pub fn sys_sigprocmask(mask: u32) -> isize {
     syscall(SYSCALL_SIGPROCMASK, [mask as usize, 0, 0])
@@ -155,3 +219,51 @@ pub fn sys_gettid() -> isize {
 pub fn sys_waittid(tid: usize) -> isize {
     syscall(SYSCALL_WAITTID, [tid, 0, 0])
 }
+
+/// return the start address of the mapping,
+/// -EEXIST when a MAP_FIXED range is already mapped,
+/// -ENOMEM when it is out of user space or there is no room,
+/// -EINVAL for bad arguments
+pub fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> isize {
+    syscall6(SYSCALL_MMAP, [addr, len, prot, flags, fd, offset])
+}
+
+/// -ENOMEM when some pages in the range are not mapped,
+/// -EACCES to make a shared mapping of a read-only file writable
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    syscall(SYSCALL_MPROTECT, [addr, len, prot])
+}
+
+/// -ENOMEM when some pages in the range are not mapped
+pub fn sys_msync(addr: usize, len: usize, flags: usize) -> isize {
+    syscall(SYSCALL_MSYNC, [addr, len, flags])
+}
+
+pub fn sys_munmap(addr: usize, len: usize) -> isize {
+    syscall(SYSCALL_MUNMAP, [addr, len, 0])
+}
+
+/// return the new program break, which stays the same on failure
+pub fn sys_brk(addr: usize) -> isize {
+    syscall(SYSCALL_BRK, [addr, 0, 0])
+}
+
+/// return the id of the segment,
+/// -ENOENT when there is none for the key and no IPC_CREAT,
+/// -EEXIST when there is one with IPC_CREAT|IPC_EXCL
+pub fn sys_shmget(key: usize, size: usize, shmflg: usize) -> isize {
+    syscall(SYSCALL_SHMGET, [key, size, shmflg])
+}
+
+pub fn sys_shmctl(shmid: usize, cmd: usize, buf: usize) -> isize {
+    syscall(SYSCALL_SHMCTL, [shmid, cmd, buf])
+}
+
+/// return the address the segment is attached at
+pub fn sys_shmat(shmid: usize, addr: usize, shmflg: usize) -> isize {
+    syscall(SYSCALL_SHMAT, [shmid, addr, shmflg])
+}
+
+pub fn sys_shmdt(addr: usize) -> isize {
+    syscall(SYSCALL_SHMDT, [addr, 0, 0])
+}